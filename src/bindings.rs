@@ -0,0 +1,114 @@
+//! Typed icon ID bindings (TypeScript and Rust), so that typos in icon names
+//! are caught by the compiler instead of at runtime.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::sprite::{IconMetadata, SpriteMetadata};
+
+fn is_stretchable(icon: &IconMetadata) -> bool {
+    icon.properties.stretch_x.is_some() || icon.properties.stretch_y.is_some()
+}
+
+fn js_string(s: &str) -> String {
+    serde_json::Value::from(s).to_string()
+}
+
+/// Returns a TypeScript union type of the string literals, `never` if there are none.
+fn union_type<'a, I: Iterator<Item=&'a String>>(ids: I) -> String {
+    let literals: Vec<String> = ids.map(|id| format!("\n  | {}", js_string(id))).collect();
+    if literals.is_empty() {
+        " never".to_owned()
+    } else {
+        literals.concat()
+    }
+}
+
+/// Generates a TypeScript module, or only the declarations for a `.d.ts` path.
+pub fn typescript(path: &Path, metadata: &SpriteMetadata) -> String {
+    let declaration = path.to_string_lossy().ends_with(".d.ts");
+    let icons = &metadata.icons;
+    let mut result = String::from("/* Generated by resprite */\n\n");
+    let _ = writeln!(result, "export type IconId ={};\n", union_type(icons.keys()));
+    let _ = writeln!(result, "export type StretchableIconId ={};\n",
+                     union_type(icons.iter().filter(|(_, i)| is_stretchable(i)).map(|(id, _)| id)));
+    let _ = writeln!(result, "export type SdfIconId ={};\n",
+                     union_type(icons.iter().filter(|(_, i)| i.properties.sdf).map(|(id, _)| id)));
+
+    let flags_type = "Readonly<Record<IconId, { stretchable: boolean; sdf: boolean }>>";
+    if declaration {
+        result.push_str("export declare const ICON_IDS: readonly IconId[];\n");
+        let _ = writeln!(result, "export declare const ICONS: {};", flags_type);
+        return result;
+    }
+    result.push_str("export const ICON_IDS: readonly IconId[] = [\n");
+    for id in icons.keys() {
+        let _ = writeln!(result, "  {},", js_string(id));
+    }
+    result.push_str("];\n\n");
+    let _ = writeln!(result, "export const ICONS: {} = {{", flags_type);
+    for (id, icon) in icons.iter() {
+        let _ = writeln!(result, "  {}: {{ stretchable: {}, sdf: {} }},",
+                         js_string(id), is_stretchable(icon), icon.properties.sdf);
+    }
+    result.push_str("};\n");
+    result
+}
+
+/// Converts an icon ID to an UpperCamelCase Rust identifier.
+fn variant_name(id: &str) -> String {
+    let mut result = String::new();
+    for part in id.split(|c: char| !c.is_ascii_alphanumeric()).filter(|p| !p.is_empty()) {
+        let mut chars = part.chars();
+        result.extend(chars.next().map(|c| c.to_ascii_uppercase()));
+        result.push_str(chars.as_str());
+    }
+    if !result.starts_with(|c: char| c.is_ascii_alphabetic()) || result == "Self" {
+        result.insert_str(0, "Icon");
+    }
+    result
+}
+
+/// Generates a Rust module with an `Icon` enum.
+pub fn rust(metadata: &SpriteMetadata) -> Result<String> {
+    let mut variants: BTreeMap<String, &String> = BTreeMap::new();
+    for id in metadata.icons.keys() {
+        let name = variant_name(id);
+        if let Some(other) = variants.insert(name.clone(), id) {
+            bail!("Icons {:?} and {:?} would both be named Icon::{}", other, id, name);
+        }
+    }
+    // Keep the ID order
+    let mut variants: Vec<(&String, String)> = variants.into_iter().map(|(n, id)| (id, n)).collect();
+    variants.sort();
+
+    let icons = &metadata.icons;
+    let mut result = String::from("// Generated by resprite\n\n");
+    result.push_str("#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]\n");
+    result.push_str("pub enum Icon {\n");
+    for (_, name) in variants.iter() {
+        let _ = writeln!(result, "    {},", name);
+    }
+    result.push_str("}\n\nimpl Icon {\n");
+    let _ = writeln!(result, "    pub const ALL: [Icon; {}] = [", variants.len());
+    for (_, name) in variants.iter() {
+        let _ = writeln!(result, "        Icon::{},", name);
+    }
+    result.push_str("    ];\n");
+
+    let mut method = |signature: &str, value: &dyn Fn(&str) -> String| {
+        let _ = writeln!(result, "\n    pub fn {} {{\n        match self {{", signature);
+        for (id, name) in variants.iter() {
+            let _ = writeln!(result, "            Icon::{} => {},", name, value(id));
+        }
+        result.push_str("        }\n    }\n");
+    };
+    method("id(self) -> &'static str", &|id| format!("{:?}", id));
+    method("is_stretchable(self) -> bool", &|id| is_stretchable(&icons[id]).to_string());
+    method("is_sdf(self) -> bool", &|id| icons[id].properties.sdf.to_string());
+    result.push_str("}\n");
+    Ok(result)
+}
@@ -0,0 +1,75 @@
+//! `check-style` subcommand: cross-checks a style against existing sprite metadata.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::sprite::SpriteMetadata;
+use crate::{name_pd, pd, style};
+
+fn is_power_of_two(size: f64) -> bool {
+    size >= 1. && size.fract() == 0. && (size as u64).is_power_of_two()
+}
+
+pub fn run(style_path: &Path, sprite_path: &Path) -> Result<()> {
+    let style_json: serde_json::Value = serde_json::from_slice(&std::fs::read(style_path)?)?;
+    let references = style::collect_references(&style_json)
+        .map_err(|e| anyhow!("{}: {}", pd(style_path), e))?;
+    let sprite = SpriteMetadata::load(sprite_path)
+        .map_err(|e| anyhow!("{}: {}", pd(sprite_path), e))?;
+
+    let style_name = name_pd(style_path);
+    let mut missing = 0;
+    let mut warnings = BTreeSet::new();
+    for reference in references.iter() {
+        if reference.is_unconstrained() {
+            println!("{}: {} cannot be resolved statically", style_name, reference);
+            continue;
+        }
+        let mut found = false;
+        for (id, icon) in sprite.icons.iter().filter(|(id, _)| reference.matches(id)) {
+            found = true;
+            if reference.is_pattern() && !(is_power_of_two(icon.width)
+                    && is_power_of_two(icon.height)) {
+                warnings.insert(format!(
+                    "layer '{}' {} uses icon {} of size {}x{}, which is not a power of two",
+                    reference.layer_id, reference.property, id, icon.width, icon.height));
+            }
+            if reference.text_fit {
+                if icon.properties.stretch_x.is_none() {
+                    warnings.insert(format!(
+                        "layer '{}' uses icon-text-fit with icon {} without stretchX",
+                        reference.layer_id, id));
+                }
+                if icon.properties.content.is_none() {
+                    warnings.insert(format!(
+                        "layer '{}' uses icon-text-fit with icon {} without content",
+                        reference.layer_id, id));
+                }
+            }
+        }
+        if !found {
+            println!("{}: {} has no matching icon", style_name, reference);
+            missing += 1;
+        }
+    }
+    for warning in warnings.iter() {
+        println!("{}: {}", style_name, warning);
+    }
+
+    let mut unused = 0;
+    if !references.iter().any(style::IconReference::is_unconstrained) {
+        for id in sprite.icons.keys().filter(|id| !style::is_referenced(&references, id)) {
+            println!("{}: icon {} is not used by the style", name_pd(sprite_path), id);
+            unused += 1;
+        }
+    }
+
+    println!("{} missing icons, {} unused icons, {} warnings",
+             missing, unused, warnings.len());
+    if missing > 0 {
+        bail!("Style {} references {} missing icons", pd(style_path), missing);
+    }
+    Ok(())
+}
@@ -0,0 +1,213 @@
+//! Composite icons built from templates, like a marker pin with a pictogram.
+//!
+//! A recipe file lists the combinations to build:
+//!
+//! ```json
+//! [{"templates": ["pin-red", "pin-blue"], "pictograms": ["poi-*"],
+//!   "color": "#ffffff", "id": "{template}-{pictogram}"}]
+//! ```
+//!
+//! Each pictogram is embedded as a nested `<svg>` centred in the content area
+//! of the template (its sidecar `content`, or the whole viewBox), optionally
+//! with its solid colours replaced. The composite keeps the sidecar metadata of
+//! the template. The sources stay icons of their own, unless a style (`--style`)
+//! only references the composite icons.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+use xmltree::{Element, EmitterConfig, XMLNode};
+
+use crate::{pd, symbols, SvgSource};
+
+/// A recipe of composite icons, as in the recipe file.
+pub struct Recipe {
+    templates: Vec<String>,
+    pictograms: Vec<String>,
+    color: Option<String>,
+    id: String,
+}
+
+/// A single composite icon to build.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Composition {
+    pub id: String,
+    pub template: String,
+    pub pictogram: String,
+    color: Option<String>,
+}
+
+fn string_list(value: &Value, name: &str) -> Result<Vec<String>> {
+    value.get(name)
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow!("\"{}\" is not an array", name))?
+        .iter()
+        .map(|item| item.as_str().map(str::to_owned)
+            .ok_or_else(|| anyhow!("{} is not a string", item)))
+        .collect()
+}
+
+impl Recipe {
+    fn from_json(value: &Value) -> Result<Self> {
+        let string = |name: &str| -> Result<Option<String>> {
+            value.get(name).map(|v| {
+                v.as_str().map(str::to_owned).ok_or_else(|| anyhow!("\"{}\" is not a string", name))
+            }).transpose()
+        };
+        let color = string("color")?;
+        if let Some(ref color) = color {
+            color.parse::<svgtypes::Color>()
+                .map_err(|e| anyhow!("Invalid colour {:?}: {}", color, e))?;
+        }
+        let id = string("id")?.unwrap_or_else(|| "{template}-{pictogram}".to_owned());
+        if !id.contains("{template}") || !id.contains("{pictogram}") {
+            bail!("ID pattern {:?} must contain {{template}} and {{pictogram}}", id);
+        }
+        Ok(Self {
+            templates: string_list(value, "templates")?,
+            pictograms: string_list(value, "pictograms")?,
+            color,
+            id,
+        })
+    }
+}
+
+/// Loads the recipes from a JSON file.
+pub fn load(path: &Path) -> Result<Vec<Recipe>> {
+    let value: Value = serde_json::from_slice(&std::fs::read(path)?)
+        .map_err(|e| anyhow!("{}: {}", pd(path), e))?;
+    value.as_array()
+        .ok_or_else(|| anyhow!("{}: recipes are not a JSON array", pd(path)))?
+        .iter()
+        .enumerate()
+        .map(|(idx, recipe)| Recipe::from_json(recipe)
+            .map_err(|e| anyhow!("{}: recipe #{}: {}", pd(path), idx + 1, e)))
+        .collect()
+}
+
+/// Returns the IDs matching the patterns (IDs, or prefixes ending with `*`).
+fn matching<'a>(patterns: &[String], ids: &'a [String]) -> Result<Vec<&'a String>> {
+    let mut result = vec![];
+    for pattern in patterns.iter() {
+        let matches: Vec<&String> = match pattern.strip_suffix('*') {
+            Some(prefix) => ids.iter().filter(|id| id.starts_with(prefix)).collect(),
+            None => ids.iter().filter(|id| *id == pattern).collect(),
+        };
+        if matches.is_empty() {
+            bail!("No SVG icon matches {:?}", pattern);
+        }
+        result.extend(matches);
+    }
+    Ok(result)
+}
+
+/// Expands the recipes into the composite icons, given the IDs of the SVG icons.
+pub fn plan(recipes: &[Recipe], svg_ids: &[String]) -> Result<Vec<Composition>> {
+    let mut result = vec![];
+    for recipe in recipes.iter() {
+        for template in matching(&recipe.templates, svg_ids)? {
+            for pictogram in matching(&recipe.pictograms, svg_ids)? {
+                if template == pictogram {
+                    continue;
+                }
+                result.push(Composition {
+                    id: recipe.id.replace("{template}", template)
+                        .replace("{pictogram}", pictogram),
+                    template: template.clone(),
+                    pictogram: pictogram.clone(),
+                    color: recipe.color.clone(),
+                });
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Replaces the paint colours (not `none` or paint servers) of `fill` and `stroke`.
+fn recolor(element: &mut Element, color: &str) {
+    let is_color = |value: &str| {
+        let value = value.trim();
+        value != "none" && !value.starts_with("url(")
+    };
+    for name in ["fill", "stroke"] {
+        if let Some(value) = element.attributes.get_mut(name) {
+            if is_color(value) {
+                *value = color.to_owned();
+            }
+        }
+    }
+    if let Some(style) = element.attributes.get_mut("style") {
+        *style = style.split(';')
+            .map(|declaration| match declaration.split_once(':') {
+                Some((name, value)) if ["fill", "stroke"].contains(&name.trim())
+                    && is_color(value) => format!("{}:{}", name, color),
+                _ => declaration.to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+    }
+    for node in element.children.iter_mut() {
+        if let XMLNode::Element(child) = node {
+            recolor(child, color);
+        }
+    }
+}
+
+fn build(composition: &Composition, template: &SvgSource, pictogram: &SvgSource)
+         -> Result<SvgSource> {
+    let (template_root, template_rect) = symbols::embeddable_root(template)?;
+    let (mut pictogram_root, pictogram_rect) = symbols::embeddable_root(pictogram)?;
+    // Sidecar metadata of SVG icons is in user units
+    let [x1, y1, x2, y2] = template.properties.content.unwrap_or([
+        template_rect.x(), template_rect.y(), template_rect.right(), template_rect.bottom()]);
+
+    if let Some(ref color) = composition.color {
+        recolor(&mut pictogram_root, color);
+        pictogram_root.attributes.entry("fill".to_owned()).or_insert_with(|| color.clone());
+        pictogram_root.attributes.insert("color".to_owned(), color.clone());
+    }
+    let mut nested = pictogram_root;
+    nested.attributes.retain(|name, _| !symbols::DROPPED_ROOT_ATTRIBUTES.contains(&name.as_str()));
+    // The nested viewport centres the pictogram, preserving its aspect ratio
+    for (name, value) in [("x", x1), ("y", y1), ("width", x2 - x1), ("height", y2 - y1)] {
+        nested.attributes.insert(name.to_owned(), value.to_string());
+    }
+    nested.attributes.insert("viewBox".to_owned(), format!(
+        "{} {} {} {}", pictogram_rect.x(), pictogram_rect.y(),
+        pictogram_rect.width(), pictogram_rect.height()));
+
+    let mut root = symbols::new_root();
+    root.attributes = template_root.attributes;
+    root.children = template_root.children;
+    root.children.push(XMLNode::Element(nested));
+    let mut svg_data = vec![];
+    root.write_with_config(&mut svg_data, EmitterConfig::new()
+        .write_document_declaration(false))?;
+
+    Ok(SvgSource {
+        id: composition.id.clone(),
+        input_path: template.input_path.clone(),
+        svg_data: Arc::new(svg_data),
+        properties: template.properties.clone(),
+        fit: template.fit,
+        stylesheet: template.stylesheet.clone(),
+    })
+}
+
+/// Builds the composite icons from the loaded SVG icons.
+pub fn build_all(compositions: &[Composition], svgs: &[SvgSource]) -> Result<Vec<SvgSource>> {
+    let find = |id: &str| svgs.iter().find(|source| source.id == id)
+        .ok_or_else(|| anyhow!("Composite icon source {} is not loaded", id));
+    let result: Vec<SvgSource> = compositions.iter()
+        .map(|composition| {
+            build(composition, find(&composition.template)?, find(&composition.pictogram)?)
+                .map_err(|e| anyhow!("Composite icon {}: {}", composition.id, e))
+        })
+        .collect::<Result<_>>()?;
+    if !result.is_empty() {
+        println!("Composed {} icons from templates", result.len());
+    }
+    Ok(result)
+}
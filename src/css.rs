@@ -0,0 +1,121 @@
+//! CSS sprite sheet: one class per icon, for showing the icons in HTML.
+//!
+//! The hi-res atlas has a layout of its own, so it is selected by a media
+//! query overriding the positions, while `image-set()` only chooses between
+//! the image formats of an atlas.
+
+use std::fmt::Write;
+use std::path::{Component, Path};
+
+use anyhow::{anyhow, Result};
+
+use crate::encode::ImageFormat;
+use crate::BuiltAtlas;
+
+/// Escapes a string to be used as (a part of) a CSS identifier.
+fn css_identifier(s: &str, first: bool) -> String {
+    let mut result = String::with_capacity(s.len());
+    for (idx, c) in s.chars().enumerate() {
+        let leading_digit = first && idx == 0 && c.is_ascii_digit();
+        if c.is_ascii_alphanumeric() && !leading_digit || c == '-' || c == '_' || !c.is_ascii() {
+            result.push(c);
+        } else {
+            let _ = write!(result, "\\{:x} ", c as u32);
+        }
+    }
+    result
+}
+
+fn css_url(url: &str) -> String {
+    format!("url(\"{}\")", url.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Returns the URL of a file relative to a directory, both existing.
+fn relative_url(from_dir: &Path, to: &Path) -> Result<String> {
+    let file_name = to.file_name().ok_or_else(|| anyhow!("Missing file name"))?;
+    let canonical = |dir: &Path| {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        std::fs::canonicalize(dir).map_err(|e| anyhow!("{}: {}", crate::pd(dir), e))
+    };
+    let from = canonical(from_dir)?;
+    let to = canonical(to.parent().unwrap_or_else(|| Path::new("")))?.join(file_name);
+    let common = from.components().zip(to.components()).take_while(|(a, b)| a == b).count();
+    let mut parts: Vec<String> = from.components().skip(common).map(|_| "..".to_owned()).collect();
+    for component in to.components().skip(common) {
+        match component {
+            Component::Normal(part) => parts.push(part.to_string_lossy().into_owned()),
+            _ => return Err(anyhow!("Can not make a relative URL to {}", crate::pd(&to))),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+fn css_offset(position: f64) -> String {
+    if position == 0. {
+        "0".to_owned()
+    } else {
+        format!("-{}px", position)
+    }
+}
+
+fn mime_type(format: &ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "image/png",
+        ImageFormat::WebP { .. } => "image/webp",
+        ImageFormat::Avif { .. } => "image/avif",
+    }
+}
+
+/// Writes the rules of an atlas, all the selectors sharing the atlas image.
+fn write_atlas(css: &mut String, atlas: &BuiltAtlas, selectors: &[String],
+               urls: &[(String, &ImageFormat)], indent: &str) {
+    let ratio = atlas.pixel_ratio;
+    let _ = writeln!(css, "{}{} {{", indent, selectors.join(&format!(",\n{}", indent)));
+    if indent.is_empty() {
+        css.push_str("  display: inline-block;\n  background-repeat: no-repeat;\n");
+    }
+    // The last format is the fallback for browsers without image-set() support
+    let _ = writeln!(css, "{}  background-image: {};", indent, css_url(&urls[urls.len() - 1].0));
+    if urls.len() > 1 {
+        let set: Vec<String> = urls.iter()
+            .map(|(url, format)| format!("{} type(\"{}\")", css_url(url), mime_type(format)))
+            .collect();
+        let _ = writeln!(css, "{}  background-image: image-set({});", indent, set.join(", "));
+    }
+    let _ = writeln!(css, "{}  background-size: {}px {}px;", indent,
+                     atlas.image.width() as f64 / ratio, atlas.image.height() as f64 / ratio);
+    let _ = writeln!(css, "{}}}", indent);
+    for (selector, icon) in selectors.iter().zip(atlas.metadata.icons.values()) {
+        let _ = writeln!(css, "{}{} {{ width: {}px; height: {}px; background-position: {} {}; }}",
+                         indent, selector, icon.width / ratio, icon.height / ratio,
+                         css_offset(icon.x / ratio), css_offset(icon.y / ratio));
+    }
+}
+
+/// Generates the CSS for the atlases (which all share the icon IDs),
+/// referencing the atlas images relative to the CSS file.
+pub fn generate(path: &Path, atlases: &[BuiltAtlas], formats: &[ImageFormat],
+                prefix: &str) -> Result<String> {
+    let css_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut css = String::from("/* Generated by resprite */\n");
+    for atlas in atlases.iter() {
+        let selectors: Vec<String> = atlas.metadata.icons.keys()
+            .map(|id| format!(".{}{}", css_identifier(prefix, true),
+                              css_identifier(id, prefix.is_empty())))
+            .collect();
+        let mut urls = vec![];
+        for format in formats.iter() {
+            let image_path = atlas.output_base.with_extension(format.extension());
+            urls.push((relative_url(css_dir, &image_path)?, format));
+        }
+        if atlas.pixel_ratio == 1. {
+            write_atlas(&mut css, atlas, &selectors, &urls, "");
+        } else {
+            let _ = writeln!(css, "@media (min-resolution: {}dppx), (-webkit-min-device-pixel-ratio: {}) {{",
+                             atlas.pixel_ratio, atlas.pixel_ratio);
+            write_atlas(&mut css, atlas, &selectors, &urls, "  ");
+            css.push_str("}\n");
+        }
+    }
+    Ok(css)
+}
@@ -0,0 +1,222 @@
+//! `diff` subcommand: compares two sprites icon by icon.
+//!
+//! Icons are matched by ID, so the atlas layouts may differ.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use resvg::tiny_skia::{Pixmap, PremultipliedColorU8};
+
+use crate::extract::is_safe_file_name;
+use crate::preview::{base64_encode, escape_html};
+use crate::sprite::SpriteMetadata;
+use crate::{create_pixmap, encode, pd};
+
+pub struct DiffOptions<'a> {
+    /// Maximum per-channel difference of pixels considered equal
+    pub tolerance: u8,
+    pub images_dir: Option<&'a Path>,
+    pub html_path: Option<&'a Path>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Added,
+    Removed,
+    Resized,
+    Changed,
+    MetadataChanged,
+    Unchanged,
+}
+
+impl Status {
+    fn name(&self) -> &'static str {
+        match self {
+            Status::Added => "added",
+            Status::Removed => "removed",
+            Status::Resized => "resized",
+            Status::Changed => "changed",
+            Status::MetadataChanged => "metadata changed",
+            Status::Unchanged => "unchanged",
+        }
+    }
+}
+
+struct IconDiff {
+    id: String,
+    status: Status,
+    details: String,
+    old: Option<Pixmap>,
+    new: Option<Pixmap>,
+    /// Changed pixels highlighted over the faded new image
+    mask: Option<Pixmap>,
+}
+
+/// Compares two images of the same size, returning the number
+/// of changed pixels and the highlighted difference.
+fn compare_pixels(old: &Pixmap, new: &Pixmap, tolerance: u8) -> Result<(usize, Pixmap)> {
+    let mut mask = create_pixmap(new.width() as f64, new.height() as f64)?;
+    let mut changed = 0;
+    let pixels = old.pixels().iter().zip(new.pixels()).zip(mask.pixels_mut());
+    for ((a, b), m) in pixels {
+        let delta = [
+            a.red().abs_diff(b.red()),
+            a.green().abs_diff(b.green()),
+            a.blue().abs_diff(b.blue()),
+            a.alpha().abs_diff(b.alpha()),
+        ].into_iter().max().unwrap_or(0);
+        *m = if delta > tolerance {
+            changed += 1;
+            PremultipliedColorU8::from_rgba(255, 0, 0, 255).unwrap()
+        } else {
+            // Unchanged pixels are shown at a quarter of their opacity
+            PremultipliedColorU8::from_rgba(b.red() / 4, b.green() / 4, b.blue() / 4,
+                                            b.alpha() / 4).unwrap()
+        };
+    }
+    Ok((changed, mask))
+}
+
+/// Places the images side by side, separated by a gap.
+fn side_by_side(images: &[&Pixmap]) -> Result<Pixmap> {
+    const GAP: u32 = 4;
+    let width = images.iter().map(|i| i.width() + GAP).sum::<u32>().saturating_sub(GAP);
+    let height = images.iter().map(|i| i.height()).max().unwrap_or(0);
+    let mut result = create_pixmap(width as f64, height as f64)?;
+    let mut x = 0;
+    for image in images {
+        // The following casts are saturating
+        result.draw_pixmap(x as i32, 0, image.as_ref(), &Default::default(),
+                           Default::default(), None)
+            .ok_or_else(|| anyhow!("Copying diff image failed"))?;
+        x += image.width() + GAP;
+    }
+    Ok(result)
+}
+
+fn diff_icons(old: &SpriteMetadata, old_atlas: &Pixmap,
+              new: &SpriteMetadata, new_atlas: &Pixmap,
+              tolerance: u8) -> Result<Vec<IconDiff>> {
+    let ids: BTreeSet<&String> = old.icons.keys().chain(new.icons.keys()).collect();
+    let mut result = vec![];
+    for id in ids {
+        let old_icon = old.icons.get(id);
+        let new_icon = new.icons.get(id);
+        let old_image = old_icon.map(|icon| icon.cut(old_atlas)).transpose()
+            .map_err(|e| anyhow!("Old icon {}: {}", id, e))?;
+        let new_image = new_icon.map(|icon| icon.cut(new_atlas)).transpose()
+            .map_err(|e| anyhow!("New icon {}: {}", id, e))?;
+        let mut mask = None;
+        let (status, details) = match (old_icon, new_icon) {
+            (None, Some(icon)) => (Status::Added, format!("{}x{}", icon.width, icon.height)),
+            (Some(icon), None) => (Status::Removed, format!("{}x{}", icon.width, icon.height)),
+            (Some(a), Some(b)) if a.width != b.width || a.height != b.height => {
+                (Status::Resized, format!("{}x{} -> {}x{}", a.width, a.height, b.width, b.height))
+            },
+            (Some(a), Some(b)) => {
+                let (changed, diff_mask) = compare_pixels(
+                    old_image.as_ref().unwrap(), new_image.as_ref().unwrap(), tolerance)?;
+                if changed > 0 {
+                    mask = Some(diff_mask);
+                    (Status::Changed, format!("{} of {} pixels differ",
+                                              changed, a.width * a.height))
+                } else if a.properties != b.properties {
+                    (Status::MetadataChanged, format!("{} -> {}",
+                                                      a.properties.to_json(),
+                                                      b.properties.to_json()))
+                } else {
+                    (Status::Unchanged, String::new())
+                }
+            },
+            (None, None) => unreachable!(),
+        };
+        result.push(IconDiff {
+            id: id.clone(),
+            status,
+            details,
+            old: old_image,
+            new: new_image,
+            mask,
+        });
+    }
+    Ok(result)
+}
+
+fn write_images(dir: &Path, diffs: &[IconDiff]) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for diff in diffs.iter().filter(|d| d.status <= Status::Changed) {
+        if !is_safe_file_name(&diff.id) {
+            println!("Skipping diff image of {:?}: not usable as a file name", diff.id);
+            continue;
+        }
+        let images: Vec<&Pixmap> = [&diff.old, &diff.new, &diff.mask].into_iter()
+            .flatten().collect();
+        let image = side_by_side(&images)?;
+        std::fs::write(dir.join(format!("{}.png", diff.id)), encode::png(&image)?)?;
+    }
+    Ok(())
+}
+
+fn image_cell(image: &Option<Pixmap>) -> Result<String> {
+    Ok(match image {
+        Some(image) => format!(
+            r#"<td><img src="data:image/png;base64,{}" width="{}" height="{}" alt=""></td>"#,
+            base64_encode(&encode::png(image)?), image.width() * 2, image.height() * 2),
+        None => "<td></td>".to_owned(),
+    })
+}
+
+fn write_html(path: &Path, title: &str, diffs: &[IconDiff]) -> Result<()> {
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    let _ = writeln!(html, "<title>{}</title>", escape_html(title));
+    html.push_str("<style>body { font-family: sans-serif; } \
+        td, th { border-bottom: 1px solid #ccc; padding: 4px 8px; text-align: left; } \
+        img { image-rendering: pixelated; background: #eee; }</style>\n</head>\n<body>\n");
+    let _ = writeln!(html, "<h1>{}</h1>", escape_html(title));
+    html.push_str("<table>\n<tr><th>ID</th><th>Status</th><th>Details</th>\
+        <th>Old</th><th>New</th><th>Difference</th></tr>\n");
+    for diff in diffs.iter().filter(|d| d.status != Status::Unchanged) {
+        let _ = writeln!(html, "<tr><td>{}</td><td>{}</td><td>{}</td>{}{}{}</tr>",
+                         escape_html(&diff.id), diff.status.name(), escape_html(&diff.details),
+                         image_cell(&diff.old)?, image_cell(&diff.new)?,
+                         image_cell(&diff.mask)?);
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    println!("Saving {}", pd(path));
+    std::fs::write(path, html)?;
+    Ok(())
+}
+
+pub fn run(old_metadata_path: &Path, old_image_path: &Path,
+           new_metadata_path: &Path, new_image_path: &Path,
+           options: &DiffOptions) -> Result<()> {
+    let (old, old_atlas) = SpriteMetadata::load_with_image(old_metadata_path, old_image_path)?;
+    let (new, new_atlas) = SpriteMetadata::load_with_image(new_metadata_path, new_image_path)?;
+    let mut diffs = diff_icons(&old, &old_atlas, &new, &new_atlas, options.tolerance)?;
+    diffs.sort_by(|a, b| a.status.cmp(&b.status).then_with(|| a.id.cmp(&b.id)));
+
+    for diff in diffs.iter().filter(|d| d.status != Status::Unchanged) {
+        if diff.details.is_empty() {
+            println!("{}: {}", diff.id, diff.status.name());
+        } else {
+            println!("{}: {} ({})", diff.id, diff.status.name(), diff.details);
+        }
+    }
+    let count = |status| diffs.iter().filter(|d| d.status == status).count();
+    println!("{} added, {} removed, {} resized, {} changed, {} metadata changed, {} unchanged",
+             count(Status::Added), count(Status::Removed), count(Status::Resized),
+             count(Status::Changed), count(Status::MetadataChanged), count(Status::Unchanged));
+
+    if let Some(dir) = options.images_dir {
+        write_images(dir, &diffs)?;
+    }
+    if let Some(path) = options.html_path {
+        let title = format!("Sprite diff: {} -> {}",
+                            pd(old_metadata_path), pd(new_metadata_path));
+        write_html(path, &title, &diffs)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,221 @@
+//! Outline (halo) and drop shadow effects, generated behind each icon.
+//!
+//! The effects are computed from the alpha channel of the rendered icon: the
+//! outline dilates it by a radius, and the shadow blurs the (outlined) shape
+//! as CSS `drop-shadow` does. Icons grow by a margin fitting the effects,
+//! the same on both sides, so that the icon stays centred on its anchor.
+
+use std::str::FromStr;
+
+use resvg::tiny_skia::{Pixmap, PremultipliedColorU8};
+
+/// Parses a number of CSS pixels.
+fn parse_px(s: &str, what: &str) -> Result<f64, String> {
+    s.trim().parse::<f64>().ok().filter(|v| v.is_finite())
+        .ok_or_else(|| format!("Invalid {} {:?}, expected a number of CSS pixels", what, s))
+}
+
+/// Splits the `:COLOR` suffix of an effect, with a default colour.
+fn split_color(s: &str, default: svgtypes::Color) -> Result<(&str, svgtypes::Color), String> {
+    match s.split_once(':') {
+        Some((value, color)) => {
+            let color = color.parse()
+                .map_err(|e| format!("Invalid colour {:?}: {}", color, e))?;
+            Ok((value, color))
+        },
+        None => Ok((s, default)),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Outline {
+    /// Radius in CSS pixels
+    pub radius: f64,
+    pub color: svgtypes::Color,
+}
+
+impl FromStr for Outline {
+    type Err = String;
+
+    /// Parses `RADIUS[:COLOR]`, white by default.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (radius, color) = split_color(s, svgtypes::Color::white())?;
+        let radius = parse_px(radius, "outline radius")?;
+        if radius <= 0. {
+            return Err(format!("Invalid outline radius {}, expected a positive number", radius));
+        }
+        Ok(Self { radius, color })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Shadow {
+    /// Offset in CSS pixels
+    pub dx: f64,
+    pub dy: f64,
+    /// Blur radius in CSS pixels, twice the standard deviation
+    pub blur: f64,
+    pub color: svgtypes::Color,
+}
+
+impl FromStr for Shadow {
+    type Err = String;
+
+    /// Parses `DX,DY,BLUR[:COLOR]`, half-transparent black by default.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (values, color) = split_color(s, svgtypes::Color::new_rgba(0, 0, 0, 128))?;
+        let values: Vec<&str> = values.split(',').collect();
+        let (dx, dy, blur) = match values[..] {
+            [dx, dy, blur] => (dx, dy, blur),
+            _ => return Err(format!("Invalid shadow {:?}, expected DX,DY,BLUR[:COLOR]", s)),
+        };
+        let blur = parse_px(blur, "shadow blur")?;
+        if blur < 0. {
+            return Err(format!("Invalid shadow blur {}, expected a non-negative number", blur));
+        }
+        Ok(Self { dx: parse_px(dx, "shadow offset")?, dy: parse_px(dy, "shadow offset")?, blur, color })
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Effects {
+    pub outline: Option<Outline>,
+    pub shadow: Option<Shadow>,
+}
+
+/// Alpha channel of an image, as a coverage in 0..1.
+struct Mask {
+    width: usize,
+    height: usize,
+    alpha: Vec<f32>,
+}
+
+impl Mask {
+    fn from_pixmap(pixmap: &Pixmap) -> Self {
+        Self {
+            width: pixmap.width() as usize,
+            height: pixmap.height() as usize,
+            alpha: pixmap.pixels().iter().map(|p| p.alpha() as f32 / 255.).collect(),
+        }
+    }
+
+    fn get(&self, x: isize, y: isize) -> f32 {
+        if x < 0 || y < 0 || x >= self.width as isize || y >= self.height as isize {
+            return 0.;
+        }
+        self.alpha[y as usize * self.width + x as usize]
+    }
+
+    /// Dilates the mask by a disk, anti-aliased at a fractional radius.
+    fn dilated(&self, radius: f64) -> Self {
+        let reach = (radius + 0.5).ceil() as isize;
+        let mut disk = vec![];
+        for dy in -reach..=reach {
+            for dx in -reach..=reach {
+                let coverage = (radius + 0.5 - ((dx * dx + dy * dy) as f64).sqrt()).min(1.);
+                if coverage > 0. {
+                    disk.push((dx, dy, coverage as f32));
+                }
+            }
+        }
+        let mut alpha = Vec::with_capacity(self.alpha.len());
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                let value = disk.iter()
+                    .map(|&(dx, dy, coverage)| self.get(x + dx, y + dy) * coverage)
+                    .fold(0., f32::max);
+                alpha.push(value);
+            }
+        }
+        Self { alpha, ..*self }
+    }
+
+    /// Moves the mask by whole pixels and blurs it with a Gaussian kernel.
+    fn shadow(&self, dx: isize, dy: isize, sigma: f64) -> Self {
+        let radius = (sigma * 3.).ceil() as isize;
+        let mut kernel: Vec<f32> = (-radius..=radius)
+            .map(|d| if sigma > 0. { (-(d * d) as f64 / (2. * sigma * sigma)).exp() as f32 } else { 1. })
+            .collect();
+        let sum: f32 = kernel.iter().sum();
+        kernel.iter_mut().for_each(|k| *k /= sum);
+
+        let blur = |get: &dyn Fn(isize) -> f32| -> f32 {
+            kernel.iter().zip(-radius..=radius).map(|(k, d)| k * get(d)).sum()
+        };
+        let (width, height) = (self.width as isize, self.height as isize);
+        let mut rows = Vec::with_capacity(self.alpha.len());
+        for y in 0..height {
+            for x in 0..width {
+                rows.push(blur(&|d| self.get(x - dx + d, y - dy)));
+            }
+        }
+        let rows = Self { alpha: rows, ..*self };
+        let mut alpha = Vec::with_capacity(self.alpha.len());
+        for y in 0..height {
+            for x in 0..width {
+                alpha.push(blur(&|d| rows.get(x, y + d)));
+            }
+        }
+        Self { alpha, ..*self }
+    }
+}
+
+/// Returns `color` at a coverage, premultiplied.
+fn premultiplied(color: svgtypes::Color, coverage: f32) -> [f32; 4] {
+    let alpha = color.alpha as f32 / 255. * coverage.clamp(0., 1.);
+    [color.red as f32 * alpha, color.green as f32 * alpha, color.blue as f32 * alpha, alpha * 255.]
+}
+
+/// Composites premultiplied `top` over `bottom`.
+fn over(top: [f32; 4], bottom: [f32; 4]) -> [f32; 4] {
+    let rest = 1. - top[3] / 255.;
+    [0, 1, 2, 3].map(|i| top[i] + bottom[i] * rest)
+}
+
+impl Effects {
+    pub fn is_empty(&self) -> bool {
+        self.outline.is_none() && self.shadow.is_none()
+    }
+
+    /// Returns the margin added on each side of an icon in pixels.
+    pub fn margin(&self, pixel_ratio: f64) -> (f64, f64) {
+        let outline = self.outline.map_or(0., |o| o.radius * pixel_ratio);
+        let (mx, my) = match self.shadow {
+            Some(s) => {
+                let blur = s.blur * pixel_ratio * 1.5;
+                (outline + blur + (s.dx * pixel_ratio).abs(),
+                 outline + blur + (s.dy * pixel_ratio).abs())
+            },
+            None => (outline, outline),
+        };
+        (mx.ceil(), my.ceil())
+    }
+
+    /// Draws the effects behind the icon in the image, which must already include the margin.
+    pub fn apply(&self, pixmap: &mut Pixmap, pixel_ratio: f64) {
+        if self.is_empty() {
+            return;
+        }
+        let icon = Mask::from_pixmap(pixmap);
+        let outline = self.outline.map(|o| (icon.dilated(o.radius * pixel_ratio), o.color));
+        let shadow = self.shadow.map(|s| {
+            let shape = outline.as_ref().map_or(&icon, |(mask, _)| mask);
+            // The following casts are saturating
+            let mask = shape.shadow((s.dx * pixel_ratio).round() as isize,
+                                    (s.dy * pixel_ratio).round() as isize,
+                                    s.blur * pixel_ratio / 2.);
+            (mask, s.color)
+        });
+
+        for (idx, pixel) in pixmap.pixels_mut().iter_mut().enumerate() {
+            let mut result = [0.; 4];
+            for (mask, color) in shadow.iter().chain(outline.iter()) {
+                result = over(premultiplied(*color, mask.alpha[idx]), result);
+            }
+            let p = [pixel.red(), pixel.green(), pixel.blue(), pixel.alpha()].map(|c| c as f32);
+            let [r, g, b, a] = over(p, result).map(|c| c.round().clamp(0., 255.) as u8);
+            // Rounding keeps the colour components within the alpha
+            *pixel = PremultipliedColorU8::from_rgba(r.min(a), g.min(a), b.min(a), a).unwrap();
+        }
+    }
+}
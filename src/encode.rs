@@ -0,0 +1,479 @@
+//! Atlas image encoding.
+//!
+//! Encoder settings are fixed explicitly instead of relying on library
+//! defaults, so that the same atlas always produces identical files.
+//! WebP and AVIF encoders are optional, enabled by the `webp-lossless`,
+//! `webp-lossy` and `avif` cargo features.
+//!
+//! PNG atlases are written with the smallest lossless color type (palette,
+//! greyscale or truecolor), optionally quantised to a lossy palette.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use resvg::tiny_skia::{Pixmap, PremultipliedColorU8};
+
+use crate::quantize;
+
+/// Output image format of an atlas.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    /// Lossy WebP with the given quality (0-100), or lossless WebP
+    WebP { quality: Option<f32> },
+    /// AVIF with the given quality (0-100)
+    Avif { quality: f32 },
+}
+
+const DEFAULT_AVIF_QUALITY: f32 = 80.;
+
+/// PNG row filter.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PngFilter {
+    /// No filter for palette images, adaptive filtering otherwise
+    Auto,
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    /// Choose the filter for each row
+    Adaptive,
+}
+
+impl FromStr for PngFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "auto" => PngFilter::Auto,
+            "none" => PngFilter::None,
+            "sub" => PngFilter::Sub,
+            "up" => PngFilter::Up,
+            "average" => PngFilter::Average,
+            "paeth" => PngFilter::Paeth,
+            "adaptive" => PngFilter::Adaptive,
+            _ => return Err(format!(
+                "Unknown PNG filter {:?}, expected auto, none, sub, up, average, paeth \
+                 or adaptive", s)),
+        })
+    }
+}
+
+/// PNG deflate compression level.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PngCompression {
+    Fast,
+    Default,
+    Best,
+}
+
+impl FromStr for PngCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "fast" => PngCompression::Fast,
+            "default" => PngCompression::Default,
+            "best" => PngCompression::Best,
+            _ => return Err(format!(
+                "Unknown PNG compression level {:?}, expected fast, default or best", s)),
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PngOptions {
+    pub filter: PngFilter,
+    pub compression: PngCompression,
+    /// Minimum quality (0-100) of the lossy palette quantisation, if enabled
+    pub quantize: Option<u8>,
+}
+
+/// Atlas image output settings.
+pub struct EncoderOptions {
+    pub formats: Vec<ImageFormat>,
+    pub png: PngOptions,
+}
+
+impl EncoderOptions {
+    /// Returns `true` if the atlas image can be read back from the output
+    /// files without any loss, to be compared pixel by pixel.
+    pub fn has_lossless_png(&self) -> bool {
+        self.formats.contains(&ImageFormat::Png) && self.png.quantize.is_none()
+    }
+}
+
+/// An encoded image with a human-readable description of its encoding.
+pub struct EncodedImage {
+    pub data: Vec<u8>,
+    pub description: String,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::WebP { .. } => "webp",
+            ImageFormat::Avif { .. } => "avif",
+        }
+    }
+
+    /// Returns an error if the format is not enabled at build time.
+    fn check_supported(&self) -> Result<(), String> {
+        let (supported, feature) = match self {
+            ImageFormat::Png => return Ok(()),
+            ImageFormat::WebP { quality: None } => (cfg!(feature = "webp-lossless"), "webp-lossless"),
+            ImageFormat::WebP { quality: Some(_) } => (cfg!(feature = "webp-lossy"), "webp-lossy"),
+            ImageFormat::Avif { .. } => (cfg!(feature = "avif"), "avif"),
+        };
+        if supported {
+            Ok(())
+        } else {
+            Err(format!("{} output requires building with the `{}` feature", self, feature))
+        }
+    }
+
+    pub fn encode(&self, pixmap: &Pixmap, png_options: &PngOptions) -> Result<EncodedImage> {
+        self.check_supported().map_err(|e| anyhow!(e))?;
+        match *self {
+            ImageFormat::Png => optimized_png(pixmap, png_options),
+            #[cfg(feature = "webp-lossless")]
+            ImageFormat::WebP { quality: None } => Ok(EncodedImage {
+                data: webp_lossless(pixmap)?,
+                description: self.to_string(),
+            }),
+            #[cfg(feature = "webp-lossy")]
+            ImageFormat::WebP { quality: Some(quality) } => Ok(EncodedImage {
+                data: webp_lossy(pixmap, quality)?,
+                description: self.to_string(),
+            }),
+            #[cfg(feature = "avif")]
+            ImageFormat::Avif { quality } => Ok(EncodedImage {
+                data: avif(pixmap, quality)?,
+                description: self.to_string(),
+            }),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl std::fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageFormat::Png => write!(f, "PNG"),
+            ImageFormat::WebP { quality: None } => write!(f, "Lossless WebP"),
+            ImageFormat::WebP { quality: Some(quality) } => write!(f, "WebP (quality {})", quality),
+            ImageFormat::Avif { quality } => write!(f, "AVIF (quality {})", quality),
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    /// Parses `png`, `webp`, `webp:QUALITY`, `avif` or `avif:QUALITY`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, quality) = match s.split_once(':') {
+            Some((name, quality)) => {
+                let quality = quality.parse::<f32>().ok()
+                    .filter(|q| (0. ..=100.).contains(q))
+                    .ok_or_else(|| format!("Invalid quality {:?}, expected 0 to 100", quality))?;
+                (name, Some(quality))
+            },
+            None => (s, None),
+        };
+        let format = match (name.to_ascii_lowercase().as_str(), quality) {
+            ("png", None) => ImageFormat::Png,
+            ("webp", quality) => ImageFormat::WebP { quality },
+            ("avif", quality) => ImageFormat::Avif {
+                quality: quality.unwrap_or(DEFAULT_AVIF_QUALITY),
+            },
+            _ => return Err(format!(
+                "Unknown image format {:?}, expected png, webp[:QUALITY] or avif[:QUALITY]", s)),
+        };
+        format.check_supported()?;
+        Ok(format)
+    }
+}
+
+/// Returns the image data as straight (non-premultiplied) RGBA.
+pub fn demultiplied_rgba(pixmap: &Pixmap) -> Vec<u8> {
+    let mut result = Vec::with_capacity(pixmap.data().len());
+    for pixel in pixmap.pixels() {
+        let c = pixel.demultiply();
+        result.extend_from_slice(&[c.red(), c.green(), c.blue(), c.alpha()]);
+    }
+    result
+}
+
+/// Encodes the image as plain RGBA PNG, quickly.
+pub fn png(pixmap: &Pixmap) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut data, pixmap.width(), pixmap.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Default);
+        encoder.set_filter(png::FilterType::Sub);
+        encoder.set_adaptive_filter(png::AdaptiveFilterType::NonAdaptive);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&demultiplied_rgba(pixmap))?;
+    }
+    Ok(data)
+}
+
+/// Image data in one of the PNG color types.
+struct PngImage {
+    color: png::ColorType,
+    depth: png::BitDepth,
+    palette: Vec<u8>,
+    trns: Vec<u8>,
+    /// Packed rows of pixels
+    data: Vec<u8>,
+    description: String,
+}
+
+impl PngImage {
+    fn truecolor(rgba: &[u8], opaque: bool) -> Self {
+        let (color, data, description) = if opaque {
+            (png::ColorType::Rgb,
+             rgba.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]).collect(), "RGB")
+        } else {
+            (png::ColorType::Rgba, rgba.to_vec(), "RGBA")
+        };
+        Self {
+            color,
+            depth: png::BitDepth::Eight,
+            palette: vec![],
+            trns: vec![],
+            data,
+            description: description.to_owned(),
+        }
+    }
+
+    fn greyscale(rgba: &[u8], opaque: bool) -> Self {
+        let (color, data, description) = if opaque {
+            (png::ColorType::Grayscale, rgba.chunks_exact(4).map(|p| p[0]).collect(), "greyscale")
+        } else {
+            (png::ColorType::GrayscaleAlpha,
+             rgba.chunks_exact(4).flat_map(|p| [p[0], p[3]]).collect(), "greyscale with alpha")
+        };
+        Self {
+            color,
+            depth: png::BitDepth::Eight,
+            palette: vec![],
+            trns: vec![],
+            data,
+            description: description.to_owned(),
+        }
+    }
+
+    /// Returns the palette image if there are at most 256 distinct colors.
+    fn indexed(rgba: &[u8], width: u32) -> Option<Self> {
+        let mut colors: BTreeMap<(bool, [u8; 4]), u8> = BTreeMap::new();
+        for p in rgba.chunks_exact(4) {
+            // Translucent colors go first, so that the tRNS chunk can be shorter
+            colors.insert((p[3] == 255, [p[0], p[1], p[2], p[3]]), 0);
+            if colors.len() > 256 {
+                return None;
+            }
+        }
+        let mut palette = vec![];
+        let mut trns = vec![];
+        for (idx, ((opaque, color), index)) in colors.iter_mut().enumerate() {
+            *index = idx as u8;
+            palette.extend_from_slice(&color[..3]);
+            if !*opaque {
+                trns.push(color[3]);
+            }
+        }
+        let (depth, bits) = match colors.len() {
+            0..=2 => (png::BitDepth::One, 1),
+            3..=4 => (png::BitDepth::Two, 2),
+            5..=16 => (png::BitDepth::Four, 4),
+            _ => (png::BitDepth::Eight, 8),
+        };
+
+        // Rows are packed from the most significant bits, each starting at a byte boundary
+        let mut data = vec![];
+        for row in rgba.chunks_exact(4 * width as usize) {
+            let mut byte = 0u8;
+            let mut used_bits = 0;
+            for p in row.chunks_exact(4) {
+                let index = colors[&(p[3] == 255, [p[0], p[1], p[2], p[3]])];
+                byte |= index << (8 - bits - used_bits);
+                used_bits += bits;
+                if used_bits == 8 {
+                    data.push(byte);
+                    byte = 0;
+                    used_bits = 0;
+                }
+            }
+            if used_bits > 0 {
+                data.push(byte);
+            }
+        }
+        Some(Self {
+            color: png::ColorType::Indexed,
+            depth,
+            palette,
+            trns,
+            data,
+            description: format!("{}-bit palette ({} colors)", bits, colors.len()),
+        })
+    }
+
+    fn encode(&self, width: u32, height: u32, options: &PngOptions) -> Result<Vec<u8>> {
+        let mut result = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut result, width, height);
+            encoder.set_color(self.color);
+            encoder.set_depth(self.depth);
+            if !self.palette.is_empty() {
+                encoder.set_palette(self.palette.as_slice());
+            }
+            if !self.trns.is_empty() {
+                encoder.set_trns(self.trns.as_slice());
+            }
+            encoder.set_compression(match options.compression {
+                PngCompression::Fast => png::Compression::Fast,
+                PngCompression::Default => png::Compression::Default,
+                PngCompression::Best => png::Compression::Best,
+            });
+            let filter = match (options.filter, self.color) {
+                (PngFilter::Auto, png::ColorType::Indexed) | (PngFilter::None, _) => {
+                    Some(png::FilterType::NoFilter)
+                },
+                (PngFilter::Sub, _) => Some(png::FilterType::Sub),
+                (PngFilter::Up, _) => Some(png::FilterType::Up),
+                (PngFilter::Average, _) => Some(png::FilterType::Avg),
+                (PngFilter::Paeth, _) => Some(png::FilterType::Paeth),
+                (PngFilter::Auto, _) | (PngFilter::Adaptive, _) => None,
+            };
+            match filter {
+                Some(filter) => {
+                    encoder.set_filter(filter);
+                    encoder.set_adaptive_filter(png::AdaptiveFilterType::NonAdaptive);
+                },
+                None => {
+                    encoder.set_filter(png::FilterType::Sub);
+                    encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+                },
+            }
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&self.data)?;
+        }
+        Ok(result)
+    }
+}
+
+/// Maps the quality (0-100) to the maximum mean squared error, like libimagequant does.
+fn quality_to_mse(quality: f64) -> f64 {
+    if quality >= 100. {
+        return 0.;
+    }
+    let extra_low_quality_fudge = (0.016 / (0.001 + quality) - 0.001).max(0.);
+    (extra_low_quality_fudge + 2.5 / (210. + quality).powf(1.2) * (100.1 - quality) / 100.) / 2.
+}
+
+fn mse_to_quality(mse: f64) -> u8 {
+    (0..=100).rev().find(|&q| mse <= quality_to_mse(q as f64)).unwrap_or(0)
+}
+
+/// Maps the image to a palette of at most 256 colors, returning the mapped
+/// straight RGBA pixels and the resulting quality (0-100).
+fn quantize(pixmap: &Pixmap) -> (Vec<u8>, u8) {
+    let pixels: Vec<[u8; 4]> = pixmap.pixels().iter()
+        .map(|p| [p.red(), p.green(), p.blue(), p.alpha()])
+        .collect();
+    let (palette, indices, mse) = quantize::quantize(&pixels, 256);
+    let palette: Vec<[u8; 4]> = palette.iter()
+        .map(|&[r, g, b, a]| {
+            let c = PremultipliedColorU8::from_rgba(r, g, b, a).unwrap().demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+    let mapped = indices.iter().flat_map(|&idx| palette[idx as usize]).collect();
+    (mapped, mse_to_quality(mse))
+}
+
+/// Encodes the image as PNG with the smallest lossless color type,
+/// or as a quantised palette image if allowed by the options.
+pub fn optimized_png(pixmap: &Pixmap, options: &PngOptions) -> Result<EncodedImage> {
+    let (width, height) = (pixmap.width(), pixmap.height());
+    let rgba = demultiplied_rgba(pixmap);
+    let opaque = rgba.chunks_exact(4).all(|p| p[3] == 255);
+    let grey = rgba.chunks_exact(4).all(|p| p[0] == p[1] && p[1] == p[2]);
+
+    let mut candidates = vec![PngImage::truecolor(&rgba, opaque)];
+    if grey {
+        candidates.push(PngImage::greyscale(&rgba, opaque));
+    }
+    match (PngImage::indexed(&rgba, width), options.quantize) {
+        (Some(image), _) => candidates.push(image),
+        (None, Some(min_quality)) => {
+            let (mapped, quality) = quantize(pixmap);
+            if quality >= min_quality {
+                let mut image = PngImage::indexed(&mapped, width)
+                    .expect("quantised image with too many colors");
+                image.description = format!("{}, lossy quality {}", image.description, quality);
+                candidates.push(image);
+            } else {
+                println!("Palette quantisation quality {} is below {}, keeping lossless PNG",
+                         quality, min_quality);
+            }
+        },
+        (None, None) => {},
+    }
+
+    // The quick encoding is a candidate too, so the result is never larger than it
+    let mut best = EncodedImage { data: png(pixmap)?, description: "PNG, RGBA".to_owned() };
+    for candidate in candidates {
+        let data = candidate.encode(width, height, options)?;
+        if data.len() < best.data.len() {
+            best = EncodedImage {
+                data,
+                description: format!("PNG, {}", candidate.description),
+            };
+        }
+    }
+    Ok(best)
+}
+
+#[cfg(feature = "webp-lossless")]
+fn webp_lossless(pixmap: &Pixmap) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    image_webp::WebPEncoder::new(&mut data).encode(
+        &demultiplied_rgba(pixmap), pixmap.width(), pixmap.height(),
+        image_webp::ColorType::Rgba8)?;
+    Ok(data)
+}
+
+#[cfg(feature = "webp-lossy")]
+fn webp_lossy(pixmap: &Pixmap, quality: f32) -> Result<Vec<u8>> {
+    let rgba = demultiplied_rgba(pixmap);
+    let data = webp::Encoder::from_rgba(&rgba, pixmap.width(), pixmap.height())
+        .encode_simple(false, quality)
+        .map_err(|e| anyhow!("WebP encoding failed: {:?}", e))?;
+    Ok(data.to_vec())
+}
+
+#[cfg(feature = "avif")]
+fn avif(pixmap: &Pixmap, quality: f32) -> Result<Vec<u8>> {
+    let pixels: Vec<ravif::RGBA8> = pixmap.pixels().iter()
+        .map(|pixel| {
+            let c = pixel.demultiply();
+            ravif::RGBA8::new(c.red(), c.green(), c.blue(), c.alpha())
+        })
+        .collect();
+    let image = ravif::Img::new(pixels.as_slice(),
+                                pixmap.width() as usize, pixmap.height() as usize);
+    let encoded = ravif::Encoder::new()
+        .with_quality(quality)
+        .with_alpha_quality(quality)
+        .encode_rgba(image)?;
+    Ok(encoded.avif_file)
+}
@@ -0,0 +1,45 @@
+//! `extract` subcommand: splits an existing sprite back into individual icons.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::{encode, pd};
+use crate::sprite::SpriteMetadata;
+
+/// Returns the base file name (without an extension) for an icon image.
+pub fn icon_file_stem(id: &str, pixel_ratio: f64) -> String {
+    if pixel_ratio == 1. {
+        id.to_owned()
+    } else {
+        format!("{}@{}x", id, pixel_ratio)
+    }
+}
+
+/// Returns `true` if the icon ID can be safely used as a file name.
+pub fn is_safe_file_name(id: &str) -> bool {
+    !id.is_empty() && id != "." && id != ".."
+        && !id.contains(['/', '\\', '\x00'])
+}
+
+pub fn run(metadata_path: &Path, image_path: &Path, output_dir: &Path) -> Result<()> {
+    let (sprite, atlas) = SpriteMetadata::load_with_image(metadata_path, image_path)?;
+    std::fs::create_dir_all(output_dir)?;
+
+    let mut count = 0;
+    for (id, icon) in sprite.icons.iter() {
+        if !is_safe_file_name(id) {
+            println!("Skipping icon {:?}: not usable as a file name", id);
+            continue;
+        }
+        let file_stem = icon_file_stem(id, icon.properties.pixel_ratio);
+        let png_path: PathBuf = output_dir.join(format!("{}.png", file_stem));
+        let sidecar_path: PathBuf = output_dir.join(format!("{}.json", file_stem));
+        let image = icon.cut(&atlas).map_err(|e| anyhow!("Icon {}: {}", id, e))?;
+        std::fs::write(&png_path, encode::png(&image)?)?;
+        std::fs::write(&sidecar_path, icon.properties.to_json().to_string())?;
+        count += 1;
+    }
+    println!("Extracted {} icons to {}", count, pd(output_dir));
+    Ok(())
+}
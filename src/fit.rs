@@ -0,0 +1,89 @@
+//! Uniform sizing of SVG icons from different sources.
+//!
+//! Icons are scaled to a target width, height or bounding box in CSS pixels,
+//! preserving the aspect ratio, for all icons or for those in a directory.
+//!
+//! The target is resolved as the `px` size of an SVG file is, which stays the
+//! same at every pixel ratio (only physical units follow the DPI of the ratio).
+//! A fitted icon thus has the size of an unfitted one with that `px` size in
+//! the atlases of all ratios.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use resvg::usvg;
+
+/// Target size of an icon in CSS pixels.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FitSize {
+    Width(f64),
+    Height(f64),
+    Box(f64, f64),
+}
+
+/// Target size of the icons in a directory, or of all the other icons.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FitSpec {
+    pub dir: Option<PathBuf>,
+    pub size: FitSize,
+}
+
+impl FromStr for FitSpec {
+    type Err = String;
+
+    /// Parses `[DIR=]SIZE` with SIZE as `WIDTHx`, `xHEIGHT` or `WIDTHxHEIGHT`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (dir, size) = match s.rsplit_once('=') {
+            Some((dir, size)) => (Some(PathBuf::from(dir)), size),
+            None => (None, s),
+        };
+        let invalid = || format!("Invalid icon size {:?}, expected [DIR=]WIDTHx, \
+                                  [DIR=]xHEIGHT or [DIR=]WIDTHxHEIGHT in CSS pixels", s);
+        let (width, height) = size.split_once(['x', 'X']).ok_or_else(invalid)?;
+        let parse = |v: &str| -> Result<Option<f64>, String> {
+            if v.is_empty() {
+                return Ok(None);
+            }
+            match v.parse::<f64>() {
+                Ok(v) if v > 0. && v.is_finite() => Ok(Some(v)),
+                _ => Err(invalid()),
+            }
+        };
+        let size = match (parse(width)?, parse(height)?) {
+            (Some(width), Some(height)) => FitSize::Box(width, height),
+            (Some(width), None) => FitSize::Width(width),
+            (None, Some(height)) => FitSize::Height(height),
+            (None, None) => return Err(invalid()),
+        };
+        Ok(Self { dir, size })
+    }
+}
+
+/// Returns the target size of an icon file, from the most specific directory.
+pub fn find(specs: &[FitSpec], path: &Path) -> Option<FitSize> {
+    specs.iter()
+        .filter(|spec| spec.dir.as_ref().map_or(true, |dir| path.starts_with(dir)))
+        .max_by_key(|spec| spec.dir.as_ref().map_or(0, |dir| dir.components().count() + 1))
+        .map(|spec| spec.size)
+}
+
+impl FitSize {
+    pub fn fit_to(&self) -> usvg::FitTo {
+        // The following casts are saturating
+        let px = |v: f64| v.round().max(1.) as u32;
+        match *self {
+            FitSize::Width(width) => usvg::FitTo::Width(px(width)),
+            FitSize::Height(height) => usvg::FitTo::Height(px(height)),
+            FitSize::Box(width, height) => usvg::FitTo::Size(px(width), px(height)),
+        }
+    }
+
+    /// Scales the size of a loaded SVG, the viewBox keeps mapping the drawing to it.
+    pub fn apply(&self, tree: &mut usvg::Tree) {
+        let fitted = self.fit_to().fit_to(tree.size.to_screen_size())
+            .and_then(|size| usvg::Size::new(size.width() as f64, size.height() as f64));
+        if let Some(size) = fitted {
+            tree.size = size;
+        }
+    }
+}
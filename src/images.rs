@@ -0,0 +1,81 @@
+//! Resource policy for `<image>` elements in SVG icons.
+//!
+//! Data URIs are always allowed, while file references must be relative paths
+//! resolving to a file under the directory of the SVG, so that an icon can not
+//! pull in arbitrary files. PNG, JPEG and GIF images are only rendered with the
+//! `raster-images` feature; every dropped image is reported.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use resvg::usvg::{self, ImageHrefResolver, ImageKind};
+
+/// Problems found while loading an SVG, shared with the resolver.
+pub type Problems = Arc<Mutex<Vec<String>>>;
+
+/// Reports the dropped images of an SVG into the problems, if given.
+#[derive(Clone)]
+struct Reporter {
+    svg_path: PathBuf,
+    problems: Option<Problems>,
+}
+
+impl Reporter {
+    fn drop(&self, image: &str, reason: &str) -> Option<ImageKind> {
+        if let Some(ref problems) = self.problems {
+            problems.lock().unwrap().push(format!("dropped image {}: {}", image, reason));
+        }
+        None
+    }
+
+    /// Checks that the loaded image can be rendered.
+    fn check(&self, image: &str, kind: Option<ImageKind>) -> Option<ImageKind> {
+        match kind {
+            None => self.drop(image, "not a valid PNG, JPEG, GIF or SVG image"),
+            Some(ImageKind::SVG(tree)) => Some(ImageKind::SVG(tree)),
+            Some(_) if !cfg!(feature = "raster-images") => {
+                self.drop(image, "raster images require the raster-images feature")
+            },
+            kind => kind,
+        }
+    }
+}
+
+/// Returns the path of a referenced file, if the policy allows it.
+fn allowed_path(svg_path: &Path, href: &str) -> Result<PathBuf, &'static str> {
+    let has_scheme = href.split_once(':')
+        .map_or(false, |(scheme, _)| !scheme.contains(['/', '\\']));
+    if has_scheme || Path::new(href).has_root() {
+        return Err("only relative file paths are allowed");
+    }
+    let dir = svg_path.parent().filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let dir = std::fs::canonicalize(dir).map_err(|_| "the input directory is not accessible")?;
+    let path = std::fs::canonicalize(dir.join(href)).map_err(|_| "file not found")?;
+    if !path.starts_with(&dir) {
+        return Err("the file is outside of the input directory");
+    }
+    Ok(path)
+}
+
+/// Creates the image resolver for an SVG file, collecting the dropped images into `problems`.
+pub fn href_resolver(svg_path: &Path, problems: Option<Problems>) -> ImageHrefResolver {
+    let reporter = Reporter { svg_path: svg_path.to_path_buf(), problems };
+    let data_reporter = reporter.clone();
+    let resolve_data = ImageHrefResolver::default_data_resolver();
+    let resolve_string = ImageHrefResolver::default_string_resolver();
+    ImageHrefResolver {
+        resolve_data: Box::new(move |mime: &str, data: Arc<Vec<u8>>, opts: &usvg::Options| {
+            let image = format!("embedded {}", mime);
+            data_reporter.check(&image, resolve_data(mime, data, opts))
+        }),
+        resolve_string: Box::new(move |href: &str, opts: &usvg::Options| {
+            let image = format!("{:?}", href);
+            match allowed_path(&reporter.svg_path, href) {
+                // The absolute path is not affected by the resources directory
+                Ok(path) => reporter.check(&image, resolve_string(&path.to_string_lossy(), opts)),
+                Err(reason) => reporter.drop(&image, reason),
+            }
+        }),
+    }
+}
@@ -0,0 +1,268 @@
+//! `lint` subcommand: checks SVG icons for problems before they reach an atlas.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use resvg::usvg::{self, roxmltree};
+use serde_json::json;
+
+use crate::{images, input_files, raster, resolve_length, svg_load_options, text};
+use crate::{pd, AtlasOptions, SvgSource};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+/// A problem of an icon.
+pub struct Finding {
+    pub path: PathBuf,
+    pub severity: Severity,
+    /// Stable identifier of the check
+    pub code: &'static str,
+    pub message: String,
+}
+
+pub struct LintOptions<'a> {
+    /// Pixel ratios the icons should have whole pixel sizes at
+    pub ratios: &'a [f64],
+    pub max_paths: usize,
+    pub css_override: Option<&'a Path>,
+    pub fonts: &'a text::Fonts,
+    pub json: bool,
+}
+
+/// Checks of a single icon, collecting the findings.
+struct IconLint<'a> {
+    source: &'a SvgSource,
+    findings: Vec<Finding>,
+}
+
+impl IconLint<'_> {
+    fn add(&mut self, severity: Severity, code: &'static str, message: String) {
+        self.findings.push(Finding {
+            path: self.source.input_path.clone(),
+            severity,
+            code,
+            message,
+        });
+    }
+
+    /// Checks the size attributes and references in the SVG source.
+    fn check_document(&mut self, document: &roxmltree::Document) {
+        let root = document.root_element();
+        let missing: Vec<&str> = ["width", "height"].into_iter()
+            .filter(|name| root.attribute(*name).is_none())
+            .collect();
+        if !missing.is_empty() {
+            let fallback = if root.has_attribute("viewBox") {
+                "the viewBox size"
+            } else {
+                "the default 100x100 size"
+            };
+            self.add(Severity::Warning, "missing-size",
+                     format!("no {} attribute, falling back to {}", missing.join("/"), fallback));
+        }
+        for name in ["width", "height"] {
+            let value = match root.attribute(name) {
+                Some(value) => value,
+                None => continue,
+            };
+            let problem = match svgtypes::Length::from_str(value) {
+                Ok(length) => resolve_length(&length, 1.).err().map(|e| e.to_string()),
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(problem) = problem {
+                self.add(Severity::Error, "unsupported-unit",
+                         format!("{} {:?}: {}", name, value, problem));
+            }
+        }
+
+        let mut references = BTreeSet::new();
+        for node in document.descendants() {
+            for attribute in node.attributes() {
+                let value = attribute.value();
+                let external = if attribute.name() == "href" {
+                    !value.starts_with('#') && !value.starts_with("data:")
+                } else {
+                    has_external_url(value)
+                };
+                if external {
+                    references.insert(value.to_owned());
+                }
+            }
+            if node.has_tag_name("style") {
+                let css = node.text().unwrap_or_default();
+                if css.contains("@import") || has_external_url(css) {
+                    references.insert("<style> @import or url()".to_owned());
+                }
+            }
+        }
+        for reference in references {
+            self.add(Severity::Warning, "external-reference",
+                     format!("references {:?} outside of the file", reference));
+        }
+    }
+
+    /// Checks the rendered content at the base pixel ratio.
+    fn check_tree(&mut self, tree: &usvg::Tree, options: &LintOptions) {
+        let paths: Vec<usvg::Path> = tree.root.descendants()
+            .filter_map(|node| match *node.borrow() {
+                usvg::NodeKind::Path(ref path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+        if paths.len() > options.max_paths {
+            self.add(Severity::Warning, "path-count",
+                     format!("{} paths, more than {}", paths.len(), options.max_paths));
+        }
+
+        if self.source.properties.sdf {
+            let mut colors = BTreeSet::new();
+            let paints = paths.iter()
+                .flat_map(|path| [path.fill.as_ref().map(|f| &f.paint),
+                                  path.stroke.as_ref().map(|s| &s.paint)])
+                .flatten();
+            for paint in paints {
+                match paint {
+                    usvg::Paint::Color(c) if (c.red, c.green, c.blue) == (0, 0, 0) => {},
+                    usvg::Paint::Color(c) => {
+                        colors.insert(format!("#{:02x}{:02x}{:02x}", c.red, c.green, c.blue));
+                    },
+                    _ => {
+                        colors.insert("gradient or pattern".to_owned());
+                    },
+                }
+            }
+            if !colors.is_empty() {
+                self.add(Severity::Warning, "sdf-color",
+                         format!("SDF icon uses hard-coded colours ({}), \
+                                  which are replaced by icon-color",
+                                 colors.into_iter().collect::<Vec<_>>().join(", ")));
+            }
+        }
+    }
+
+    fn run(&mut self, options: &LintOptions) {
+        let data = self.source.svg_data.as_slice();
+        let data = data.strip_prefix(&[0xEF, 0xBB, 0xBF]).unwrap_or(data);
+        match std::str::from_utf8(data).map_err(|e| e.to_string())
+            .and_then(|s| roxmltree::Document::parse(s).map_err(|e| e.to_string())) {
+            Ok(document) => self.check_document(&document),
+            Err(e) => {
+                self.add(Severity::Error, "invalid-svg", e);
+                return;
+            },
+        }
+
+        for (idx, &ratio) in options.ratios.iter().enumerate() {
+            let atlas_options = AtlasOptions {
+                pixel_ratio: ratio,
+                buffer_px: 0.,
+                snap: None,
+                effects: Default::default(),
+            };
+            let problems = images::Problems::default();
+            let svg_options = svg_load_options(&atlas_options, options.fonts,
+                                               &self.source.input_path, Some(problems.clone()));
+            let mut tree = match usvg::Tree::from_data(&self.source.svg_data, &svg_options) {
+                Ok(tree) => tree,
+                Err(e) => {
+                    self.add(Severity::Error, "invalid-svg", e.to_string());
+                    return;
+                },
+            };
+            let (width, height) = (tree.size.width(), tree.size.height());
+            if width.fract() != 0. || height.fract() != 0. {
+                self.add(Severity::Warning, "fractional-size",
+                         format!("size {}x{} at @{}x is not a whole number of pixels",
+                                 width, height, ratio));
+            }
+            if idx > 0 {
+                continue;
+            }
+            let text_problems = options.fonts.convert_text(&mut tree);
+            for problem in problems.lock().unwrap().iter() {
+                self.add(Severity::Warning, "image", problem.clone());
+            }
+            for problem in text_problems {
+                self.add(Severity::Warning, "text", problem);
+            }
+            self.check_tree(&tree, options);
+        }
+    }
+}
+
+/// Returns `true` if the CSS or attribute value has a `url()` not pointing into the document.
+fn has_external_url(value: &str) -> bool {
+    value.match_indices("url(").any(|(idx, _)| {
+        let target = value[idx + 4..].trim_start_matches(|c: char| c.is_whitespace()
+                                                              || c == '"' || c == '\'');
+        !target.starts_with('#')
+    })
+}
+
+fn print_findings(findings: &[Finding], json: bool) {
+    if json {
+        let findings: Vec<serde_json::Value> = findings.iter()
+            .map(|finding| json!({
+                "path": finding.path.to_string_lossy(),
+                "severity": finding.severity.to_string(),
+                "code": finding.code,
+                "message": finding.message,
+            }))
+            .collect();
+        println!("{}", serde_json::Value::Array(findings));
+        return;
+    }
+    for finding in findings.iter() {
+        println!("{}: {}: {} [{}]", pd(&finding.path), finding.severity, finding.message,
+                 finding.code);
+    }
+}
+
+pub fn run(input_paths: &[PathBuf], options: &LintOptions) -> Result<()> {
+    let mut findings = vec![];
+    let svg_files = input_files(input_paths)?.into_iter()
+        .filter(|path| !raster::is_raster(path));
+    for path in svg_files {
+        let source = match SvgSource::load(path.clone(), options.css_override, false) {
+            Ok(source) => source,
+            Err(e) => {
+                findings.push(Finding {
+                    path,
+                    severity: Severity::Error,
+                    code: "invalid-svg",
+                    message: e.to_string(),
+                });
+                continue;
+            },
+        };
+        let mut lint = IconLint { source: &source, findings: vec![] };
+        lint.run(options);
+        findings.extend(lint.findings);
+    }
+    print_findings(&findings, options.json);
+
+    let errors = findings.iter().filter(|f| f.severity == Severity::Error).count();
+    if !options.json {
+        println!("{} errors, {} warnings", errors, findings.len() - errors);
+    }
+    if errors > 0 {
+        bail!("Lint found {} errors", errors);
+    }
+    Ok(())
+}
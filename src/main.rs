@@ -8,7 +8,6 @@ use globwalk::GlobWalkerBuilder;
 #[cfg(feature = "parallel")]
 use rayon::prelude::*;
 use resvg::usvg;
-use serde_json::json;
 
 mod cli {
     use std::path::PathBuf;
@@ -16,7 +15,7 @@ mod cli {
     use bpaf::Bpaf;
 
     #[derive(Bpaf)]
-    #[bpaf(generate(config_parser), options, version)]
+    #[bpaf(generate(command_parser), options, version)]
     /// Build a Mapbox sprite atlas from an input directory of SVGs.
     ///
     ///
@@ -29,20 +28,229 @@ mod cli {
     ///     Hi-res resolution atlas metadata (in case of --with-hires)
     ///   ${output%.*}@2x.png
     ///     Hi-res resolution atlas (in case of --with-hires)
+    /// With --format, the atlas images are written in each of the given formats
+    /// (png, webp, webp:QUALITY or avif[:QUALITY]) sharing the same metadata.
+    /// With --metadata-format, the metadata is written in each of the given formats
+    /// (texturepacker-hash and -array as .hash.json and .array.json, libgdx as .atlas
+    /// and starling as .xml) instead of the Mapbox JSON.
     /// SVG file names will be used as icon identifiers in the resulting atlas.
+    /// Pre-rendered PNG icons (name.png, name@2x.png) can be used as inputs too.
+    /// A name.json sidecar may specify sdf, stretchX/Y and content of an icon
+    /// (in SVG user units or PNG pixels, with pixelRatio for PNG icons).
+    // Parsed once, not worth boxing the configuration
+    #[allow(clippy::large_enum_variant)]
+    pub enum Command {
+        #[bpaf(command("check-style"))]
+        /// Cross-check a style against an existing sprite metadata file
+        CheckStyle {
+            /// MapLibre/Mapbox style JSON
+            #[bpaf(positional("STYLE"))]
+            style: PathBuf,
+            /// Sprite metadata JSON
+            #[bpaf(positional("SPRITE"))]
+            sprite: PathBuf,
+        },
+        #[bpaf(command("extract"))]
+        /// Split an existing sprite into PNG icons with metadata sidecars
+        Extract {
+            /// Output directory
+            #[bpaf(short, long, argument("DIR"))]
+            output: PathBuf,
+            /// Sprite metadata JSON
+            #[bpaf(positional("SPRITE JSON"))]
+            metadata: PathBuf,
+            /// Sprite atlas image
+            #[bpaf(positional("SPRITE PNG"))]
+            image: PathBuf,
+        },
+        #[bpaf(command("diff"))]
+        /// Compare two sprites icon by icon
+        Diff {
+            /// Maximum per-channel difference of pixels considered unchanged
+            #[bpaf(long, argument("N"), fallback(0))]
+            tolerance: u8,
+            /// Write side-by-side images of the differing icons into a directory
+            #[bpaf(long("images"), argument("DIR"))]
+            images_dir: Option<PathBuf>,
+            /// Write an HTML summary
+            #[bpaf(long, argument("PATH"))]
+            html: Option<PathBuf>,
+            /// Old sprite metadata JSON
+            #[bpaf(positional("OLD JSON"))]
+            old_metadata: PathBuf,
+            /// Old sprite atlas image
+            #[bpaf(positional("OLD PNG"))]
+            old_image: PathBuf,
+            /// New sprite metadata JSON
+            #[bpaf(positional("NEW JSON"))]
+            new_metadata: PathBuf,
+            /// New sprite atlas image
+            #[bpaf(positional("NEW PNG"))]
+            new_image: PathBuf,
+        },
+        #[bpaf(command("lint"))]
+        /// Check SVG icons for problems before they reach an atlas
+        Lint {
+            /// Pixel ratio the icon sizes should be whole pixels at, can be repeated
+            /// (1 and 2 by default)
+            #[bpaf(long("ratio"), argument("N"))]
+            ratios: Vec<f64>,
+            /// Report icons with more paths than this
+            #[bpaf(long, argument("N"), fallback(500))]
+            max_paths: usize,
+            /// Override the XML stylesheet in SVG files
+            #[bpaf(long("css"), argument("PATH"))]
+            css_override: Option<PathBuf>,
+            /// Directory with fonts for text in SVGs (text feature), can be repeated
+            #[bpaf(long, argument("DIR"))]
+            font_dirs: Vec<PathBuf>,
+            /// Font file for text in SVGs (text feature), can be repeated
+            #[bpaf(long("font"), argument("PATH"))]
+            fonts: Vec<PathBuf>,
+            /// Print the findings as a JSON array
+            #[bpaf(long, switch)]
+            json: bool,
+            /// Input directory with SVG files, can be repeated
+            #[bpaf(positional("SVG DIR"))]
+            svg_dirs: Vec<PathBuf>,
+        },
+        #[bpaf(command("serve"))]
+        /// Serve the sprite over HTTP, building the atlases on demand
+        /// (/NAME.json, /NAME.png and /NAME@Nx.json, /NAME@Nx.png for any ratio N)
+        Serve {
+            /// Port to listen on (on localhost)
+            #[bpaf(long, argument("PORT"), fallback(8080))]
+            port: u16,
+            /// Base name of the sprite URLs
+            #[bpaf(long, argument("NAME"), fallback("sprite".to_owned()))]
+            name: String,
+            #[bpaf(external(sprite_options))]
+            sprite: SpriteOptions,
+            /// Verbose console output
+            #[bpaf(short, long, switch)]
+            verbose: bool,
+            /// Input directory with SVG or PNG files, can be repeated
+            #[bpaf(positional("SVG DIR"))]
+            svg_dirs: Vec<PathBuf>,
+        },
+        Build(#[bpaf(external(config_parser))] Config),
+    }
+
+    /// Options of the icon sources and of their rendering, shared by build and serve.
+    #[derive(Bpaf)]
+    #[bpaf(generate(sprite_options))]
+    pub struct SpriteOptions {
+        /// Override the XML stylesheet in SVG files
+        #[bpaf(long("css"), argument("PATH"))]
+        pub css_override: Option<PathBuf>,
+        /// Additional buffer (padding) size
+        #[bpaf(long, argument("LENGTH"))]
+        pub buffer: Option<svgtypes::Length>,
+        /// Snap the SVG drawings to whole pixels at each ratio: align (move them
+        /// by the sub-pixel offset) or center (centre them in the icon)
+        #[bpaf(long, argument("MODE"))]
+        pub snap_to_pixels: Option<crate::snap::SnapMode>,
+        /// Draw an outline (halo) around the icons: RADIUS[:COLOR] in CSS px, white by default
+        #[bpaf(long, argument("RADIUS[:COLOR]"))]
+        pub outline: Option<crate::effects::Outline>,
+        /// Draw a drop shadow behind the icons: DX,DY,BLUR[:COLOR] in CSS px
+        /// (blur as in CSS drop-shadow), half-transparent black by default
+        #[bpaf(long, argument("DX,DY,BLUR[:COLOR]"))]
+        pub shadow: Option<crate::effects::Shadow>,
+        /// Scale the SVG icons (of a directory) to a width (24x), height (x24)
+        /// or bounding box (24x24) in CSS px, can be repeated for several directories;
+        /// as with px-sized SVGs, the size is the same at every pixel ratio
+        #[bpaf(long("fit-size"), argument("[DIR=]SIZE"))]
+        pub fit_sizes: Vec<crate::fit::FitSpec>,
+        /// Build composite icons (a pictogram in a template) from a JSON recipe file
+        #[bpaf(long, argument("PATH"))]
+        pub compose: Option<PathBuf>,
+        /// Add a variant of the icons (of a directory) with a suffixed ID, made with
+        /// space-separated filters: greyscale, desaturate(AMOUNT), opacity(AMOUNT),
+        /// tint(COLOR) or matrix(20 VALUES); can be repeated. A suffix starting with
+        /// "-" needs the --variant=-SUFFIX:FILTERS form
+        #[bpaf(long("variant"), argument("[DIR=]SUFFIX:FILTERS"))]
+        pub variants: Vec<crate::variants::Variant>,
+        /// Only include icons referenced by a MapLibre/Mapbox style
+        #[bpaf(long, argument("PATH"))]
+        pub style: Option<PathBuf>,
+        /// Merge the icons of an existing sprite (base path of its JSON and PNG,
+        /// including the @2x ones if present), can be repeated
+        #[bpaf(long("sprite"), argument("PATH"))]
+        pub sprites: Vec<PathBuf>,
+        /// Directory with fonts for text in SVGs (text feature), can be repeated
+        #[bpaf(long, argument("DIR"))]
+        pub font_dirs: Vec<PathBuf>,
+        /// Font file for text in SVGs (text feature), can be repeated;
+        /// the family of the first font is the fallback
+        #[bpaf(long("font"), argument("PATH"))]
+        pub fonts: Vec<PathBuf>,
+    }
+
+    #[derive(Bpaf)]
+    #[bpaf(generate(config_parser))]
     pub struct Config {
         /// Base output file path (with or without an extension)
         #[bpaf(short, long, argument("PATH"))]
         pub output: PathBuf,
-        /// Override the XML stylesheet in SVG files
-        #[bpaf(long("css"), argument("PATH"))]
-        pub css_override: Option<PathBuf>,
+        #[bpaf(external(sprite_options))]
+        pub sprite: SpriteOptions,
         // TODO: custom base scale
         #[bpaf(switch)]
         pub with_hires: bool,
-        /// Additional buffer (padding) size
-        #[bpaf(long, argument("LENGTH"))]
-        pub buffer: Option<svgtypes::Length>,
+        /// Keep the icon positions of a previous sprite (base path of its JSON,
+        /// including the @2x one if present) to minimise changes of the atlas
+        #[bpaf(long, argument("PATH"))]
+        pub seed_layout: Option<PathBuf>,
+        /// Repack the seeded atlas from scratch if its fill ratio falls below this value
+        #[bpaf(long, argument("RATIO"), fallback(0.5))]
+        pub min_fill_ratio: f64,
+        /// Atlas image format: png, webp (lossless, with the webp-lossless feature),
+        /// webp:QUALITY (with the webp-lossy feature) or avif[:QUALITY] (with the avif
+        /// feature), can be repeated to write several formats side by side
+        #[bpaf(long("format"), argument("FORMAT"))]
+        pub formats: Vec<crate::encode::ImageFormat>,
+        /// Metadata format: mapbox, texturepacker-hash, texturepacker-array, libgdx
+        /// or starling, can be repeated to write several formats side by side
+        #[bpaf(long("metadata-format"), argument("FORMAT"))]
+        pub metadata_formats: Vec<Box<dyn crate::metadata::MetadataFormat>>,
+        /// PNG row filter: auto, none, sub, up, average, paeth or adaptive
+        #[bpaf(long, argument("FILTER"), fallback(crate::encode::PngFilter::Auto))]
+        pub png_filter: crate::encode::PngFilter,
+        /// PNG deflate compression level: fast, default or best
+        #[bpaf(long, argument("LEVEL"), fallback(crate::encode::PngCompression::Best))]
+        pub png_compression: crate::encode::PngCompression,
+        /// Quantise PNG atlases to a lossy palette,
+        /// unless the quality (0-100) would fall below this value
+        #[bpaf(long, argument("MIN_QUALITY"))]
+        pub quantize: Option<u8>,
+        /// Write each icon into a directory as {id}@{ratio}x.png
+        #[bpaf(long, argument("DIR"))]
+        pub icons_dir: Option<PathBuf>,
+        /// Do not write the atlas files (with --icons-dir)
+        #[bpaf(long, switch)]
+        pub no_atlas: bool,
+        /// Write a CSS sprite sheet with a class per icon
+        #[bpaf(long, argument("PATH"))]
+        pub css_sprite: Option<PathBuf>,
+        /// Class name prefix of the CSS sprite sheet
+        #[bpaf(long, argument("PREFIX"), fallback("icon-".to_owned()))]
+        pub css_prefix: String,
+        /// Write an SVG sprite with a <symbol> per SVG icon
+        #[bpaf(long, argument("PATH"))]
+        pub svg_sprite: Option<PathBuf>,
+        /// Write the icon IDs as TypeScript types (declarations only for a .d.ts path)
+        #[bpaf(long, argument("PATH"))]
+        pub typescript: Option<PathBuf>,
+        /// Write the icon IDs as a Rust module with an Icon enum
+        #[bpaf(long, argument("PATH"))]
+        pub rust_module: Option<PathBuf>,
+        /// Write an HTML preview of the atlases and icons
+        #[bpaf(long, argument("PATH"))]
+        pub preview: Option<PathBuf>,
+        /// Verify that the existing output files are up to date instead of writing them
+        #[bpaf(long, switch)]
+        pub check: bool,
         /// Verbose console output
         #[bpaf(short, long, switch)]
         pub verbose: bool,
@@ -50,35 +258,74 @@ mod cli {
         #[cfg(feature = "parallel")]
         #[bpaf(short('j'), long, argument("N"), fallback(0))]
         pub threads: usize,
-        /// Input directory with SVG files, can be repeated
+        /// Input directory with SVG or PNG files, can be repeated
         #[bpaf(positional("SVG DIR"))]
         pub svg_dirs: Vec<PathBuf>,
     }
-
 }
 
 struct SvgSource {
+    id: String,
     input_path: PathBuf,
     svg_data: Arc<Vec<u8>>,
+    properties: sprite::IconProperties,
+    /// Target size of the icon, if normalised
+    fit: Option<fit::FitSize>,
+    /// Stylesheet inlined into the SVG data
+    stylesheet: Option<PathBuf>,
+}
+
+/// All icon sources of an atlas, SVG icons come first in the ID order.
+struct AtlasSources {
+    svgs: Vec<SvgSource>,
+    rasters: Vec<raster::RasterSource>,
+    fonts: Arc<text::Fonts>,
+    variants: Vec<variants::Variant>,
+    /// Stylesheets inlined into any of the loaded SVG files
+    stylesheets: Vec<PathBuf>,
 }
 
 #[derive(Copy, Clone)]
 struct AtlasOptions {
     pixel_ratio: f64,
     buffer_px: f64,
+    snap: Option<snap::SnapMode>,
+    effects: effects::Effects,
 }
 
 impl AtlasOptions {
-    fn new(args: &cli::Config, ratio: f64) -> Result<Self> {
-        let buffer_size = args.buffer.as_ref().map_or(
+    fn new(buffer: Option<&svgtypes::Length>, ratio: f64,
+           snap: Option<snap::SnapMode>, effects: effects::Effects) -> Result<Self> {
+        let buffer_size = buffer.map_or(
             Ok(0.0), |len| resolve_length(len, ratio))?;
         Ok(Self {
             pixel_ratio: ratio,
             buffer_px: buffer_size.ceil(),
+            snap,
+            effects,
         })
     }
 }
 
+/// Previous layout of an atlas, used to keep the icon positions stable.
+struct LayoutSeed {
+    metadata: sprite::SpriteMetadata,
+    min_fill_ratio: f64,
+}
+
+impl LayoutSeed {
+    /// Loads the seed from a metadata file, if it exists.
+    fn load(metadata_path: &Path, min_fill_ratio: f64) -> Result<Option<Self>> {
+        if !metadata_path.is_file() {
+            println!("No previous layout at {}, packing from scratch", pd(metadata_path));
+            return Ok(None);
+        }
+        let metadata = sprite::SpriteMetadata::load(metadata_path)
+            .map_err(|e| anyhow!("{}: {}", pd(metadata_path), e))?;
+        Ok(Some(Self { metadata, min_fill_ratio }))
+    }
+}
+
 struct AtlasSourceData {
     #[cfg(not(feature = "parallel"))]
     svg_trees: Vec<usvg::Tree>,
@@ -88,20 +335,35 @@ struct AtlasSourceData {
 
 struct PreparedSvgAtlas {
     atlas_options: AtlasOptions,
+    /// Options for loading the SVGs again, without reporting dropped images
+    #[allow(dead_code)]
+    svg_options: Vec<usvg::Options>,
     #[allow(dead_code)]
-    svg_options: usvg::Options,
+    fonts: Arc<text::Fonts>,
+    /// Size and viewBox of the SVGs after fitting and snapping, applied again when loading them
+    #[allow(dead_code)]
+    views: Vec<(usvg::Size, usvg::ViewBox)>,
     ids: Vec<String>,
+    properties: Vec<sprite::IconProperties>,
     data: AtlasSourceData,
+    rasters: Vec<Arc<resvg::tiny_skia::Pixmap>>,
+    /// Variants with the index of the icon they are made of, after the other icons
+    variants: Vec<(usize, variants::Variant)>,
     layout: potpack2::Layout,
 }
 
-fn svg_load_options(options: &AtlasOptions) -> usvg::Options {
-    usvg::Options {
+/// Returns the options for loading an SVG file, collecting the dropped images into `problems`.
+fn svg_load_options(options: &AtlasOptions, fonts: &text::Fonts,
+                    svg_path: &Path, problems: Option<images::Problems>) -> usvg::Options {
+    let mut result = usvg::Options {
         resources_dir: None,
         dpi: 96.0 * options.pixel_ratio,
         // default_size: is the default (100, 100) fine?
+        image_href_resolver: images::href_resolver(svg_path, problems),
         ..Default::default()
-    }
+    };
+    fonts.apply(&mut result);
+    result
 }
 
 fn resolve_length(length: &svgtypes::Length, pixel_ratio: f64) -> Result<f64> {
@@ -126,7 +388,29 @@ fn resolve_length(length: &svgtypes::Length, pixel_ratio: f64) -> Result<f64> {
     Ok(result)
 }
 
+mod bindings;
+mod check_style;
+mod compose;
+mod css;
+mod diff;
+mod effects;
+mod encode;
+mod extract;
+mod fit;
+mod images;
+mod lint;
+mod metadata;
 mod potpack2;
+mod preview;
+mod quantize;
+mod raster;
+mod serve;
+mod snap;
+mod sprite;
+mod style;
+mod symbols;
+mod text;
+mod variants;
 
 #[cfg(any())]
 fn dump_tree_node(usvg_node: &usvg::Node, level: usize) {
@@ -169,17 +453,19 @@ impl std::fmt::Display for PathDisplay<'_> {
     }
 }
 
-fn pd<S: AsRef<std::ffi::OsStr> + ?Sized>(s: &S) -> PathDisplay {
+fn pd<S: AsRef<std::ffi::OsStr> + ?Sized>(s: &S) -> PathDisplay<'_> {
     s.into()
 }
 
-fn name_pd(p: &Path) -> PathDisplay {
+fn name_pd(p: &Path) -> PathDisplay<'_> {
     p.file_name().map(Into::into).unwrap_or(p.into())
 }
 
+/// Inlines the XML stylesheet (or the override) into the SVG, returning the data
+/// and the path of the inlined stylesheet.
 fn patch_xml_style_sheet(fs_path: &Path,
                          css_override: Option<&Path>,
-                         verbose: bool) -> Result<Vec<u8>> {
+                         verbose: bool) -> Result<(Vec<u8>, Option<PathBuf>)> {
     use xmltree::{Element, XMLNode, EmitterConfig};
     let svg_data = std::fs::read(fs_path)?;
     let data_without_bom = svg_data.as_slice().strip_prefix(&[0xEF, 0xBB, 0xBF])
@@ -189,25 +475,24 @@ fn patch_xml_style_sheet(fs_path: &Path,
     let mut root: Option<&mut Element> = None;
     for node in svg_xml.iter_mut() {
         match node {
-            XMLNode::ProcessingInstruction(pi_name, pi_data) => {
-                if pi_name == "xml-stylesheet" && pi_data.is_some() {
-                    let scaffold = format!("<stylesheet {} />", pi_data.as_ref().unwrap());
-                    let pi_xml = match Element::parse(scaffold.as_bytes()) {
-                        Ok(xml) => { xml }
-                        Err(e) => {
-                            println!("{}: skipping invalid <?xml-stylesheet {}?> PI: {}",
-                                     name_pd(fs_path), pi_data.as_ref().unwrap(), e);
-                            continue;
-                        }
-                    };
-                    if let Some(href) = href_from_xml_stylesheet(&pi_xml) {
-                        stylesheet_path = Some((fs_path.parent().unwrap()
-                            .join(Path::new(href))).into());
-                        if verbose {
-                            println!("{}: found xml-stylesheet {}",
-                                     name_pd(fs_path),
-                                     pd(stylesheet_path.as_ref().unwrap().as_ref()));
-                        }
+            XMLNode::ProcessingInstruction(pi_name, Some(pi_data))
+                if pi_name == "xml-stylesheet" => {
+                let scaffold = format!("<stylesheet {} />", pi_data);
+                let pi_xml = match Element::parse(scaffold.as_bytes()) {
+                    Ok(xml) => { xml }
+                    Err(e) => {
+                        println!("{}: skipping invalid <?xml-stylesheet {}?> PI: {}",
+                                 name_pd(fs_path), pi_data, e);
+                        continue;
+                    }
+                };
+                if let Some(href) = href_from_xml_stylesheet(&pi_xml) {
+                    stylesheet_path = Some((fs_path.parent().unwrap()
+                        .join(Path::new(href))).into());
+                    if verbose {
+                        println!("{}: found xml-stylesheet {}",
+                                 name_pd(fs_path),
+                                 pd(stylesheet_path.as_ref().unwrap().as_ref()));
                     }
                 }
             },
@@ -222,7 +507,7 @@ fn patch_xml_style_sheet(fs_path: &Path,
         stylesheet_path = Some(css.into());
     }
     if let Some(stylesheet_path) = stylesheet_path {
-        let css_data = std::fs::read_to_string(stylesheet_path)?;
+        let css_data = std::fs::read_to_string(&stylesheet_path)?;
         let mut style_elem = Element::new("style");
         style_elem.attributes.insert("type".into(), "text/css".into());
         style_elem.children.push(XMLNode::Text(css_data));
@@ -232,16 +517,68 @@ fn patch_xml_style_sheet(fs_path: &Path,
         let mut new_svg_data: Vec<u8> = vec![];
         root.unwrap().write_with_config(&mut new_svg_data, EmitterConfig::new()
             .write_document_declaration(false))?;
-        Ok(new_svg_data)
+        Ok((new_svg_data, Some(stylesheet_path.into_owned())))
+    } else {
+        Ok((svg_data, None))
+    }
+}
+
+fn icon_id(fs_path: &Path) -> Result<String> {
+    let stem = fs_path.file_stem()
+        .ok_or_else(|| anyhow!("Missing file name {}", pd(fs_path)))?
+        .to_string_lossy();
+    if raster::is_raster(fs_path) {
+        Ok(raster::split_ratio_suffix(&stem).0.to_owned())
     } else {
-        Ok(svg_data)
+        Ok(stem.into_owned())
     }
 }
 
 impl SvgSource {
     fn load(fs_path: PathBuf, css_override: Option<&Path>, verbose: bool) -> Result<Self> {
-        let svg_data = patch_xml_style_sheet(&fs_path, css_override, verbose)?;
-        Ok(Self { input_path: fs_path, svg_data: Arc::new(svg_data) })
+        let (svg_data, stylesheet) = patch_xml_style_sheet(&fs_path, css_override, verbose)?;
+        let sidecar_path = fs_path.with_extension("json");
+        let properties = sprite::IconProperties::load_sidecar(&sidecar_path)
+            .map_err(|e| anyhow!("{}: {}", pd(&sidecar_path), e))?
+            .unwrap_or_default();
+        Ok(Self {
+            id: icon_id(&fs_path)?,
+            input_path: fs_path,
+            svg_data: Arc::new(svg_data),
+            properties,
+            fit: None,
+            stylesheet,
+        })
+    }
+}
+
+impl AtlasSources {
+    /// Orders the sources by ID, so that the atlas does not depend on the input order.
+    fn new(mut svgs: Vec<SvgSource>, mut rasters: Vec<raster::RasterSource>) -> Self {
+        svgs.sort_by(|a, b| a.id.cmp(&b.id));
+        rasters.sort_by(|a, b| a.id.cmp(&b.id));
+        Self {
+            svgs,
+            rasters,
+            fonts: Default::default(),
+            variants: vec![],
+            stylesheets: vec![],
+        }
+    }
+
+    fn ids(&self) -> impl Iterator<Item=(&str, &Path)> {
+        self.svgs.iter().map(|s| (s.id.as_str(), s.input_path.as_path()))
+            .chain(self.rasters.iter().map(|r| (r.id.as_str(), r.input_path.as_path())))
+    }
+
+    fn check_unique_ids(&self) -> Result<()> {
+        let mut seen: std::collections::HashMap<&str, &Path> = Default::default();
+        for (id, path) in self.ids() {
+            if let Some(other_path) = seen.insert(id, path) {
+                bail!("Duplicate icon ID {}: {} and {}", id, pd(other_path), pd(path));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -260,52 +597,181 @@ impl AtlasSourceData {
 }
 
 impl PreparedSvgAtlas {
-    fn new<'a, I>(options: AtlasOptions, sources: I) -> Result<Self>
-        where I: IntoIterator<Item=&'a SvgSource> + Copy {
-        let svg_options = svg_load_options(&options);
+    fn new(options: AtlasOptions, sources: &AtlasSources,
+           seed: Option<&LayoutSeed>) -> Result<Self> {
+        let mut svg_options = vec![];
+        let mut views = vec![];
         let mut svg_trees: Vec<usvg::Tree> = vec![];
         let mut ids: Vec<String> = vec![];
-        for source in sources.into_iter() {
-            svg_trees.push(usvg::Tree::from_data(&source.svg_data, &svg_options)?);
-            let source_id: String = source.input_path.file_stem()
-                .ok_or_else(|| anyhow!("Missing file name {}", pd(&source.input_path)))?
-                .to_string_lossy().into_owned();
-            ids.push(source_id);
-        }
-        let layout = layout_atlas(svg_trees.iter(), options.buffer_px);
-        if layout.items.len() != svg_trees.len() {
+        let mut properties: Vec<sprite::IconProperties> = vec![];
+        for source in sources.svgs.iter() {
+            let problems = images::Problems::default();
+            let mut tree = usvg::Tree::from_data(
+                &source.svg_data,
+                &svg_load_options(&options, &sources.fonts, &source.input_path,
+                                  Some(problems.clone())))
+                .map_err(|e| anyhow!("{}: {}", pd(&source.input_path), e))?;
+            let text_problems = sources.fonts.convert_text(&mut tree);
+            for problem in problems.lock().unwrap().iter().chain(text_problems.iter()) {
+                println!("{}: {}", pd(&source.input_path), problem);
+            }
+            if let Some(fit) = source.fit {
+                fit.apply(&mut tree);
+            }
+            // Sidecar metadata of SVG icons is in user units, mapped to the icon
+            // by the viewBox (before the snapping moves the drawing)
+            let ts = usvg::utils::view_box_to_transform(
+                tree.view_box.rect, tree.view_box.aspect, tree.size);
+            let icon_properties = source.properties.mapped(
+                (ts.a, ts.d), (ts.e, ts.f), options.pixel_ratio);
+            let snap = options.snap.and_then(|mode| snap::Snap::new(&tree, mode));
+            if let Some(ref snap) = snap {
+                if let Some(correction) = snap.correction() {
+                    println!("{}: {} at @{}x", pd(&source.input_path), correction,
+                             options.pixel_ratio);
+                }
+                snap.apply(&mut tree);
+            }
+            svg_options.push(
+                svg_load_options(&options, &sources.fonts, &source.input_path, None));
+            properties.push(match snap {
+                Some(ref snap) => icon_properties.shifted(snap.dx, snap.dy),
+                None => icon_properties,
+            });
+            views.push((tree.size, tree.view_box));
+            ids.push(source.id.clone());
+            svg_trees.push(tree);
+        }
+        let mut rasters = vec![];
+        for source in sources.rasters.iter() {
+            let (pixmap, raster_properties) = source.resolve(options.pixel_ratio)?;
+            ids.push(source.id.clone());
+            properties.push(raster_properties);
+            rasters.push(pixmap);
+        }
+        let mut variants = vec![];
+        for (idx, (id, path)) in sources.ids().enumerate() {
+            for variant in sources.variants.iter().filter(|v| v.applies_to(path)) {
+                variants.push((idx, variant.clone()));
+                ids.push(format!("{}{}", id, variant.suffix));
+                properties.push(properties[idx].clone());
+            }
+        }
+        let mut seen_ids = std::collections::HashSet::new();
+        if let Some(id) = ids.iter().find(|id| !seen_ids.insert(*id)) {
+            bail!("Duplicate icon ID {} with the variants", id);
+        }
+
+        let (margin_x, margin_y) = options.effects.margin(options.pixel_ratio);
+        let mut sizes: Vec<(f64, f64)> = svg_trees.iter()
+            .map(|tree| (tree.size.width(), tree.size.height()))
+            .chain(rasters.iter().map(|p| (p.width() as f64, p.height() as f64)))
+            .map(|(width, height)| (width + 2. * margin_x, height + 2. * margin_y))
+            .collect();
+        for &(idx, _) in variants.iter() {
+            sizes.push(sizes[idx]);
+        }
+        let layout = layout_atlas(sizes, options.buffer_px, &ids, seed);
+        if layout.items.len() != ids.len() {
             bail!("Layout error: count of input images ({}) does not match layout items count ({})",
-                svg_trees.len(), layout.items.len());
+                ids.len(), layout.items.len());
         }
         Ok(Self {
             atlas_options: options,
             svg_options,
+            fonts: sources.fonts.clone(),
+            views,
             ids,
+            properties,
             data: AtlasSourceData::new(
-                svg_trees, sources.into_iter().map(|s: &SvgSource| &s.svg_data)),
+                svg_trees, sources.svgs.iter().map(|s: &SvgSource| &s.svg_data)),
+            rasters,
+            variants,
             layout,
         })
     }
 
+    /// Returns the offset of an icon image in its layout box (the buffer and the effects margin).
+    fn icon_offset(&self) -> (f64, f64) {
+        let options = &self.atlas_options;
+        let (margin_x, margin_y) = options.effects.margin(options.pixel_ratio);
+        (options.buffer_px + margin_x, options.buffer_px + margin_y)
+    }
+
     fn render_single_svg(&self, svg: &usvg::Tree, layout_box: potpack2::Box)
         -> Result<resvg::tiny_skia::Pixmap> {
-        let buffer_px: f32 = self.atlas_options.buffer_px as f32;
+        let (x, y) = self.icon_offset();
         let mut sub_pixmap = create_pixmap(layout_box.w, layout_box.h)?;
         resvg::render(
             svg,
             usvg::FitTo::Original,
-            resvg::tiny_skia::Transform::from_translate(buffer_px, buffer_px),
+            resvg::tiny_skia::Transform::from_translate(x as f32, y as f32),
             sub_pixmap.as_mut(),
         ).ok_or_else(|| anyhow!("Rendering svg #{} failed", layout_box.id))?;
+        self.atlas_options.effects.apply(&mut sub_pixmap, self.atlas_options.pixel_ratio);
         Ok(sub_pixmap)
     }
 
+    fn draw_rasters(&self, pixmap: &mut resvg::tiny_skia::Pixmap,
+                    raster_boxes: &[potpack2::Box]) -> Result<()> {
+        let (x, y) = self.icon_offset();
+        let effects = &self.atlas_options.effects;
+        for (raster, layout_box) in self.rasters.iter().zip(raster_boxes) {
+            // The effects are drawn in the layout box
+            let mut sub_pixmap = create_pixmap(layout_box.w, layout_box.h)?;
+            // The following casts are saturating
+            sub_pixmap.draw_pixmap(
+                x as i32,
+                y as i32,
+                raster.as_ref().as_ref(),
+                &Default::default(),
+                Default::default(),
+                None,
+            ).ok_or_else(|| anyhow!("Copying raster image #{} failed", layout_box.id))?;
+            effects.apply(&mut sub_pixmap, self.atlas_options.pixel_ratio);
+            pixmap.draw_pixmap(
+                layout_box.x as i32,
+                layout_box.y as i32,
+                sub_pixmap.as_ref(),
+                &Default::default(),
+                Default::default(),
+                None,
+            ).ok_or_else(|| anyhow!("Copying raster image #{} failed", layout_box.id))?;
+        }
+        Ok(())
+    }
+
+    /// Draws the variants from the icon images already in the atlas.
+    fn draw_variants(&self, pixmap: &mut resvg::tiny_skia::Pixmap,
+                     image_boxes: &[potpack2::Box]) -> Result<()> {
+        let variant_boxes = &image_boxes[image_boxes.len() - self.variants.len()..];
+        for ((base, variant), layout_box) in self.variants.iter().zip(variant_boxes) {
+            let base_box = image_boxes[*base];
+            // The following casts are saturating
+            let mut image = resvg::tiny_skia::IntRect::from_xywh(
+                base_box.x as i32, base_box.y as i32, base_box.w as u32, base_box.h as u32)
+                .and_then(|rect| pixmap.clone_rect(rect))
+                .ok_or_else(|| anyhow!("Copying icon image #{} failed", base_box.id))?;
+            variant.apply(&mut image);
+            pixmap.draw_pixmap(
+                layout_box.x as i32,
+                layout_box.y as i32,
+                image.as_ref(),
+                &Default::default(),
+                Default::default(),
+                None,
+            ).ok_or_else(|| anyhow!("Copying variant #{} failed", layout_box.id))?;
+        }
+        Ok(())
+    }
+
     #[cfg(not(feature = "parallel"))]
     fn render(&self) -> Result<resvg::tiny_skia::Pixmap> {
         let mut pixmap = create_pixmap(self.layout.width, self.layout.height)?;
         let mut image_boxes: Vec<potpack2::Box> = self.layout.items.clone();
         image_boxes.sort_by_key(|b| b.id);
-        for (svg, layout_box) in self.data.svg_trees.iter().zip(image_boxes) {
+        let svg_count = self.data.svg_trees.len();
+        for (svg, &layout_box) in self.data.svg_trees.iter().zip(image_boxes.iter()) {
             let sub_pixmap = self.render_single_svg(svg, layout_box)?;
             // The following casts are saturating
             pixmap.draw_pixmap(
@@ -317,6 +783,8 @@ impl PreparedSvgAtlas {
                 None,
             ).ok_or_else(|| anyhow!("Copying sub-pixmap failed"))?;
         }
+        self.draw_rasters(&mut pixmap, &image_boxes[svg_count..])?;
+        self.draw_variants(&mut pixmap, &image_boxes)?;
         Ok(pixmap)
     }
 
@@ -326,9 +794,17 @@ impl PreparedSvgAtlas {
         let mut pixmap = create_pixmap(self.layout.width, self.layout.height)?;
         let mut image_boxes: Vec<potpack2::Box> = self.layout.items.clone();
         image_boxes.sort_by_key(|b| b.id);
-        let results: Vec<_> = self.data.svg_data.par_iter().zip(image_boxes)
-            .map(|(image_data, layout_box)| -> Result<(f64, f64, Pixmap)> {
-                let svg_tree = usvg::Tree::from_data(image_data, &self.svg_options)?;
+        let svg_count = self.data.svg_data.len();
+        let results: Vec<_> = self.data.svg_data.par_iter().zip(&self.svg_options)
+            .zip(&self.views)
+            .zip(image_boxes.par_iter())
+            .map(|(((image_data, svg_options), &(size, view_box)), &layout_box)|
+                 -> Result<(f64, f64, Pixmap)> {
+                let mut svg_tree = usvg::Tree::from_data(image_data, svg_options)?;
+                // The problems were reported when preparing the atlas
+                self.fonts.convert_text(&mut svg_tree);
+                svg_tree.size = size;
+                svg_tree.view_box = view_box;
                 let sub_pixmap = self.render_single_svg(&svg_tree, layout_box)?;
                 Ok((layout_box.x, layout_box.y, sub_pixmap))
             })
@@ -345,19 +821,25 @@ impl PreparedSvgAtlas {
                 None,
             ).ok_or_else(|| anyhow!("Copying result failed"))?;
         }
+        self.draw_rasters(&mut pixmap, &image_boxes[svg_count..])?;
+        self.draw_variants(&mut pixmap, &image_boxes)?;
 
         Ok(pixmap)
     }
 
-    fn metadata(&self) -> Result<serde_json::Value> {
-        let mut result = json!({});
+    fn metadata(&self) -> Result<sprite::SpriteMetadata> {
+        let mut result = sprite::SpriteMetadata::default();
+        let options = &self.atlas_options;
+        let (margin_x, margin_y) = options.effects.margin(options.pixel_ratio);
         for b in self.layout.items.iter() {
-            result[self.ids[b.id].clone()] = json!({
-                "width": b.w,
-                "height": b.h,
-                "x": b.x,
-                "y": b.y,
-                "pixelRatio": self.atlas_options.pixel_ratio,
+            result.icons.insert(self.ids[b.id].clone(), sprite::IconMetadata {
+                width: b.w,
+                height: b.h,
+                x: b.x,
+                y: b.y,
+                properties: self.properties[b.id]
+                    .transformed(1., options.buffer_px, options.pixel_ratio)
+                    .shifted(margin_x, margin_y),
             });
         }
         Ok(result)
@@ -372,22 +854,210 @@ fn create_pixmap(width: f64, height: f64) -> Result<resvg::tiny_skia::Pixmap> {
         .ok_or_else(|| anyhow!("Pixmap creation ({}x{}) failed", px_width, px_height))
 }
 
-fn layout_atlas<'a, I: IntoIterator<Item=&'a usvg::Tree>>(
-    images: I, buffer_px: f64) -> potpack2::Layout {
-    let input: Vec<_> = images
+fn layout_atlas<I: IntoIterator<Item=(f64, f64)>>(
+    sizes: I, buffer_px: f64, ids: &[String], seed: Option<&LayoutSeed>) -> potpack2::Layout {
+    let input: Vec<_> = sizes
         .into_iter()
-        .map(|image| {
+        .map(|(width, height)| {
             // Ensure that there is a configurable buffer between sprites
-            (image.size.width().ceil() + 2. * buffer_px,
-             image.size.height().ceil() + 2. * buffer_px)
+            (width.ceil() + 2. * buffer_px,
+             height.ceil() + 2. * buffer_px)
+        })
+        .collect();
+    let seed = match seed {
+        Some(seed) => seed,
+        None => return potpack2::Layout::new(input),
+    };
+
+    // Only the icons of an unchanged size keep their positions
+    let positions: Vec<Option<(f64, f64)>> = ids.iter().zip(input.iter())
+        .map(|(id, &(width, height))| {
+            seed.metadata.icons.get(id)
+                .filter(|icon| icon.width == width && icon.height == height)
+                .map(|icon| (icon.x, icon.y))
         })
         .collect();
-    potpack2::Layout::new(input)
+    let layout = potpack2::Layout::seeded(input.clone(), &positions);
+    if layout.fill_ratio < seed.min_fill_ratio {
+        println!("Fill ratio of the seeded layout is {:.2}, repacking the atlas",
+                 layout.fill_ratio);
+        return potpack2::Layout::new(input);
+    }
+    let kept = layout.items.iter()
+        .filter(|b| positions[b.id] == Some((b.x, b.y)))
+        .count();
+    println!("Kept the positions of {} of {} icons (fill ratio {:.2})",
+             kept, ids.len(), layout.fill_ratio);
+    layout
+}
+
+/// A rendered atlas with its metadata, ready to be written out.
+struct BuiltAtlas {
+    pixel_ratio: f64,
+    output_base: PathBuf,
+    metadata: sprite::SpriteMetadata,
+    image: resvg::tiny_skia::Pixmap,
+}
+
+impl BuiltAtlas {
+    /// Returns the atlas as described by the metadata files, referencing the first image format.
+    fn info(&self, options: &encode::EncoderOptions) -> metadata::AtlasInfo<'_> {
+        let image_path = self.output_base.with_extension(options.formats[0].extension());
+        metadata::AtlasInfo {
+            image_file: image_path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            width: self.image.width(),
+            height: self.image.height(),
+            pixel_ratio: self.pixel_ratio,
+            metadata: &self.metadata,
+        }
+    }
+
+    fn save(&self, options: &encode::EncoderOptions,
+            metadata_formats: &[Box<dyn metadata::MetadataFormat>]) -> Result<()> {
+        let info = self.info(options);
+        for format in metadata_formats.iter() {
+            let metadata_path = self.output_base.with_extension(format.extension());
+            std::fs::write(metadata_path, format.generate(&info))?;
+        }
+
+        let plain_size = encode::png(&self.image)?.len();
+        for format in options.formats.iter() {
+            let image_path = self.output_base.with_extension(format.extension());
+            let encoded = format.encode(&self.image, &options.png)?;
+            println!("Saving {}: {}, {} bytes ({:+.1}% compared to plain RGBA PNG)",
+                     pd(&image_path), encoded.description, encoded.data.len(),
+                     (encoded.data.len() as f64 / plain_size as f64 - 1.) * 100.);
+            std::fs::write(image_path, encoded.data)?;
+        }
+        Ok(())
+    }
+
+    /// Compares the atlas with the existing output files, returning the differences.
+    fn check(&self, options: &encode::EncoderOptions,
+             metadata_formats: &[Box<dyn metadata::MetadataFormat>]) -> Result<Vec<String>> {
+        let mut result = vec![];
+        let info = self.info(options);
+        for format in metadata_formats.iter() {
+            let metadata_path = self.output_base.with_extension(format.extension());
+            result.extend(format.check(&metadata_path, &info));
+        }
+
+        // Other formats can not be compared by pixels, as they may be lossy
+        let lossless_png = options.has_lossless_png();
+        for format in options.formats.iter()
+            .filter(|&&f| f != encode::ImageFormat::Png || !lossless_png) {
+            let image_path = self.output_base.with_extension(format.extension());
+            match std::fs::read(&image_path) {
+                Ok(existing) if existing != format.encode(&self.image, &options.png)?.data => {
+                    result.push(format!("{}: {} image differs", pd(&image_path), format));
+                },
+                Ok(_) => {},
+                Err(e) => result.push(format!("{}: {}", pd(&image_path), e)),
+            }
+        }
+        if lossless_png {
+            let png_path = self.output_base.with_extension("png");
+            result.extend(compare_png(&png_path, &self.image));
+        }
+        Ok(result)
+    }
+
+    /// Returns the images of the icons (including the buffer) with their file names.
+    fn icon_images(&self) -> Result<Vec<(String, resvg::tiny_skia::Pixmap)>> {
+        let mut result = vec![];
+        for (id, icon) in self.metadata.icons.iter() {
+            if !extract::is_safe_file_name(id) {
+                println!("Skipping icon image of {:?}: not usable as a file name", id);
+                continue;
+            }
+            let image = icon.cut(&self.image).map_err(|e| anyhow!("Icon {}: {}", id, e))?;
+            result.push((format!("{}@{}x.png", id, self.pixel_ratio), image));
+        }
+        Ok(result)
+    }
+
+    fn save_icons(&self, dir: &Path, options: &encode::PngOptions) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let icons = self.icon_images()?;
+        for (file_name, image) in icons.iter() {
+            std::fs::write(dir.join(file_name), encode::optimized_png(image, options)?.data)?;
+        }
+        println!("Saved {} icon images @{}x to {}", icons.len(), self.pixel_ratio, pd(dir));
+        Ok(())
+    }
+
+    /// Compares the icons with the existing icon images, returning the differences.
+    fn check_icons(&self, dir: &Path, options: &encode::PngOptions) -> Result<Vec<String>> {
+        let mut result = vec![];
+        for (file_name, image) in self.icon_images()? {
+            let path = dir.join(file_name);
+            if options.quantize.is_none() {
+                result.extend(compare_png(&path, &image));
+                continue;
+            }
+            match std::fs::read(&path) {
+                Ok(existing) if existing != encode::optimized_png(&image, options)?.data => {
+                    result.push(format!("{}: image differs", pd(&path)));
+                },
+                Ok(_) => {},
+                Err(e) => result.push(format!("{}: {}", pd(&path), e)),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Compares a PNG file with the image pixel by pixel, returning the difference.
+fn compare_png(path: &Path, image: &resvg::tiny_skia::Pixmap) -> Option<String> {
+    match resvg::tiny_skia::Pixmap::load_png(path) {
+        Ok(existing) if existing.width() != image.width()
+                || existing.height() != image.height() => {
+            Some(format!("{}: size {}x{} differs from {}x{}", pd(path),
+                         existing.width(), existing.height(), image.width(), image.height()))
+        },
+        Ok(existing) => {
+            let changed = existing.pixels().iter().zip(image.pixels())
+                .filter(|(a, b)| a != b)
+                .count();
+            (changed > 0).then(|| format!("{}: {} pixels differ", pd(path), changed))
+        },
+        Err(e) => Some(format!("{}: {}", pd(path), e)),
+    }
 }
 
-fn process(sources: &Vec<SvgSource>, options: AtlasOptions,
-           output_base: &Path, verbose: bool) -> Result<()> {
-    let atlas = PreparedSvgAtlas::new(options, sources)?;
+/// Compares a file with the expected contents, returning the difference.
+fn compare_file(path: &Path, contents: &[u8]) -> Option<String> {
+    match std::fs::read(path) {
+        Ok(existing) if existing != contents => Some(format!("{}: differs", pd(path))),
+        Ok(_) => None,
+        Err(e) => Some(format!("{}: {}", pd(path), e)),
+    }
+}
+
+/// Generates the requested text outputs derived from the atlases (CSS, SVG sprite, bindings).
+fn text_outputs(args: &cli::Config, sources: &AtlasSources, atlases: &[BuiltAtlas],
+                encoder_options: &encode::EncoderOptions) -> Result<Vec<(PathBuf, String)>> {
+    let mut result = vec![];
+    if let Some(ref css_path) = args.css_sprite {
+        let css = css::generate(css_path, atlases, &encoder_options.formats, &args.css_prefix)?;
+        result.push((css_path.clone(), css));
+    }
+    if let Some(ref sprite_path) = args.svg_sprite {
+        result.push((sprite_path.clone(), symbols::generate(&sources.svgs)?));
+    }
+    if let Some(ref typescript_path) = args.typescript {
+        result.push((typescript_path.clone(),
+                     bindings::typescript(typescript_path, &atlases[0].metadata)));
+    }
+    if let Some(ref rust_path) = args.rust_module {
+        result.push((rust_path.clone(), bindings::rust(&atlases[0].metadata)?));
+    }
+    Ok(result)
+}
+
+fn process(sources: &AtlasSources, options: AtlasOptions, seed: Option<&LayoutSeed>,
+           output_base: &Path, verbose: bool) -> Result<BuiltAtlas> {
+    let atlas = PreparedSvgAtlas::new(options, sources, seed)?;
     if verbose {
         println!("Atlas layout: {:?}", atlas.layout);
     } else {
@@ -396,52 +1066,302 @@ fn process(sources: &Vec<SvgSource>, options: AtlasOptions,
                  atlas.layout.height.ceil())
     }
 
-    let json_metadata = atlas.metadata()?;
-    let metadata_path = output_base.with_extension("json");
-    std::fs::write(metadata_path, json_metadata.to_string())?;
+    Ok(BuiltAtlas {
+        pixel_ratio: options.pixel_ratio,
+        output_base: output_base.to_path_buf(),
+        metadata: atlas.metadata()?,
+        image: atlas.render()?,
+    })
+}
 
-    let atlas_image = atlas.render()?;
-    let png_path = output_base.with_extension("png");
-    println!("Saving {}", pd(&png_path));
-    atlas_image.save_png(png_path)?;
+/// Returns which of the icons (with their input paths) are referenced by the style,
+/// directly or by the ID of one of their variants, reporting missing icons.
+fn referenced_by_style(ids: &[String], paths: &[&Path], variants: &[variants::Variant],
+                       style_path: &Path, verbose: bool) -> Result<Vec<bool>> {
+    let style: serde_json::Value = serde_json::from_slice(&std::fs::read(style_path)?)?;
+    let references = style::collect_references(&style)
+        .map_err(|e| anyhow!("{}: {}", pd(style_path), e))?;
+    // The IDs of each icon and of its variants
+    let icon_ids: Vec<Vec<String>> = ids.iter().zip(paths.iter())
+        .map(|(id, path)| {
+            std::iter::once(id.clone())
+                .chain(variants.iter()
+                    .filter(|variant| variant.applies_to(path))
+                    .map(|variant| format!("{}{}", id, variant.suffix)))
+                .collect()
+        })
+        .collect();
 
-    Ok(())
+    for reference in references.iter() {
+        if reference.is_unconstrained() {
+            println!("{}: {} cannot be resolved statically, keeping all icons",
+                     name_pd(style_path), reference);
+        } else if !icon_ids.iter().flatten().any(|id| reference.matches(id)) {
+            println!("{}: {} has no matching icon", name_pd(style_path), reference);
+        }
+    }
+
+    let result: Vec<bool> = icon_ids.iter()
+        .map(|ids| ids.iter().any(|id| style::is_referenced(&references, id)))
+        .collect();
+    if verbose {
+        for (id, _) in ids.iter().zip(result.iter()).filter(|(_, &used)| !used) {
+            println!("{}: icon {} is not referenced", name_pd(style_path), id);
+        }
+    }
+    println!("Style {} references {} of {} input icons",
+             pd(style_path), result.iter().filter(|&&used| used).count(), ids.len());
+    Ok(result)
+}
+
+/// Appends a suffix (e.g. `@2x`) to the file stem of an output base path.
+fn suffixed_base(base: &Path, suffix: &str) -> PathBuf {
+    let mut result = base.to_path_buf();
+    let mut file_name = base.file_stem().unwrap_or_default().to_owned();
+    file_name.push(suffix);
+    result.set_file_name(file_name);
+    result
 }
 
 fn main() -> Result<()> {
-    let args_parser: bpaf::OptionParser<cli::Config> = cli::config_parser()
+    let args_parser: bpaf::OptionParser<cli::Command> = cli::command_parser()
         .usage(concat!("Usage: ", env!("CARGO_BIN_NAME"), " {usage}"));
-    let args = args_parser.run();
+    match args_parser.run() {
+        cli::Command::CheckStyle { style, sprite } => check_style::run(&style, &sprite),
+        cli::Command::Extract { output, metadata, image } => {
+            extract::run(&metadata, &image, &output)
+        },
+        cli::Command::Diff {
+            tolerance, images_dir, html,
+            old_metadata, old_image, new_metadata, new_image,
+        } => {
+            let options = diff::DiffOptions {
+                tolerance,
+                images_dir: images_dir.as_deref(),
+                html_path: html.as_deref(),
+            };
+            diff::run(&old_metadata, &old_image, &new_metadata, &new_image, &options)
+        },
+        cli::Command::Lint {
+            ratios, max_paths, css_override, font_dirs, fonts, json, svg_dirs,
+        } => {
+            let ratios = if ratios.is_empty() { vec![1., 2.] } else { ratios };
+            if ratios.iter().any(|&ratio| ratio <= 0.) {
+                bail!("Invalid pixel ratio, expected a positive number");
+            }
+            let options = lint::LintOptions {
+                ratios: &ratios,
+                max_paths,
+                css_override: css_override.as_deref(),
+                fonts: &text::Fonts::load(&font_dirs, &fonts)?,
+                json,
+            };
+            lint::run(&svg_dirs, &options)
+        },
+        cli::Command::Serve { port, name, sprite, verbose, svg_dirs } => {
+            serve::run(&serve::ServeOptions {
+                port,
+                name: &name,
+                buffer: sprite.buffer.as_ref(),
+                snap: sprite.snap_to_pixels,
+                effects: sprite.effects(),
+                inputs: sprite.inputs(&svg_dirs, verbose),
+            })
+        },
+        cli::Command::Build(args) => build(args),
+    }
+}
 
-    if args.output.file_name().is_none() {
-        bail!("Invalid output file name: {}", pd(&args.output))
+/// Returns the SVG and PNG files of the input paths (directories or files).
+fn input_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut result = vec![];
+    for path in paths.iter() {
+        if path.is_file() {
+            result.push(path.clone());
+        } else {
+            if !path.exists() {
+                bail!("Input path does not exist: {:?}", path)
+            }
+
+            let walker = GlobWalkerBuilder::new(path, "*.{svg,png}")
+                .max_depth(1)
+                .build()?;
+
+            // Directory order is file system dependent
+            let mut dir_files: Vec<PathBuf> = walker
+                .into_iter()
+                .filter_map(|r| {
+                    r.map_err(|err| println!("{}", err)).ok()
+                        .map(|entry| entry.into_path())
+                })
+                .collect();
+            dir_files.sort();
+            result.extend(dir_files)
+        }
     }
+    Ok(result)
+}
 
-    let input_files: Vec<PathBuf> = {
-        let mut result = vec![];
-        for path in args.svg_dirs.iter() {
-            if path.is_file() {
-                result.push(path.clone());
-            } else {
-                if !path.exists() {
-                    bail!("Input path does not exist: {:?}", path)
-                }
+/// Where the icons of the atlases come from.
+struct InputOptions<'a> {
+    input_paths: &'a [PathBuf],
+    css_override: Option<&'a Path>,
+    sprites: &'a [PathBuf],
+    style: Option<&'a Path>,
+    font_dirs: &'a [PathBuf],
+    fonts: &'a [PathBuf],
+    fit_sizes: &'a [fit::FitSpec],
+    /// Recipes of composite icons
+    compose: Option<&'a Path>,
+    variants: &'a [variants::Variant],
+    /// Files and directories written by the build, which are never inputs
+    outputs: Vec<PathBuf>,
+    verbose: bool,
+}
 
-                let walker = GlobWalkerBuilder::new(path, "*.svg")
-                    .max_depth(1)
-                    .build()?;
+/// Returns `true` if an input file is one of the outputs, or in an output directory.
+fn is_output(path: &Path, outputs: &[PathBuf]) -> bool {
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    outputs.iter()
+        .filter_map(|output| output.canonicalize().ok())
+        .any(|output| path == output || (output.is_dir() && path.parent() == Some(&output)))
+}
 
-                result.extend(walker
-                    .into_iter()
-                    .filter_map(|r| {
-                        r.map_err(|err| println!("{}", err)).ok()
-                            .map(|entry| entry.into_path())
-                    }))
-            }
+impl cli::SpriteOptions {
+    fn inputs<'a>(&'a self, input_paths: &'a [PathBuf], verbose: bool) -> InputOptions<'a> {
+        InputOptions {
+            input_paths,
+            css_override: self.css_override.as_deref(),
+            sprites: &self.sprites,
+            style: self.style.as_deref(),
+            font_dirs: &self.font_dirs,
+            fonts: &self.fonts,
+            fit_sizes: &self.fit_sizes,
+            compose: self.compose.as_deref(),
+            variants: &self.variants,
+            outputs: vec![],
+            verbose,
         }
-        result
+    }
+
+    fn effects(&self) -> effects::Effects {
+        effects::Effects { outline: self.outline, shadow: self.shadow }
+    }
+}
+
+fn load_sources(inputs: &InputOptions) -> Result<AtlasSources> {
+    let mut input_files = input_files(inputs.input_paths)?;
+    input_files.retain(|path| {
+        let output = raster::is_raster(path) && is_output(path, &inputs.outputs);
+        if output {
+            println!("{}: skipping an output of the build", pd(path));
+        }
+        !output
+    });
+
+    let mut sprite_sources = vec![];
+    for sprite_base in inputs.sprites.iter() {
+        sprite_sources.extend(raster::load_sprite(sprite_base)?);
+    }
+
+    let mut compositions = match inputs.compose {
+        Some(recipes_path) => {
+            let svg_ids = input_files.iter()
+                .filter(|path| !raster::is_raster(path))
+                .map(|path| icon_id(path))
+                .collect::<Result<Vec<_>, _>>()?;
+            compose::plan(&compose::load(recipes_path)?, &svg_ids)?
+        },
+        None => vec![],
     };
 
+    // Icons only needed as parts of the composite icons are not included themselves
+    let mut part_ids: Vec<String> = vec![];
+    let input_files = match inputs.style {
+        Some(style_path) => {
+            let mut ids = input_files.iter()
+                .map(|path| icon_id(path))
+                .collect::<Result<Vec<_>, _>>()?;
+            ids.extend(sprite_sources.iter().map(|source| source.id.clone()));
+            ids.extend(compositions.iter().map(|composition| composition.id.clone()));
+            // Composite icons have the input path of their template
+            let mut paths: Vec<&Path> = input_files.iter().map(PathBuf::as_path).collect();
+            paths.extend(sprite_sources.iter().map(|source| source.input_path.as_path()));
+            for composition in compositions.iter() {
+                let template_idx = ids.iter().position(|id| *id == composition.template)
+                    .ok_or_else(|| anyhow!("Composite icon template {} is not an input file",
+                                           composition.template))?;
+                paths.push(paths[template_idx]);
+            }
+            let referenced = referenced_by_style(&ids, &paths, inputs.variants, style_path,
+                                                 inputs.verbose)?;
+            let (file_referenced, rest) = referenced.split_at(input_files.len());
+            let (sprite_referenced, composition_referenced) = rest.split_at(sprite_sources.len());
+            let mut sprite_referenced = sprite_referenced.iter();
+            sprite_sources.retain(|_| *sprite_referenced.next().unwrap());
+            let mut composition_referenced = composition_referenced.iter();
+            compositions.retain(|_| *composition_referenced.next().unwrap());
+            let is_part = |id: &String| compositions.iter()
+                .any(|composition| composition.template == *id || composition.pictogram == *id);
+            let mut kept = vec![];
+            for ((path, &used), id) in input_files.into_iter().zip(file_referenced).zip(ids) {
+                if !used && is_part(&id) {
+                    part_ids.push(id);
+                } else if !used {
+                    continue;
+                }
+                kept.push(path);
+            }
+            kept
+        },
+        None => input_files,
+    };
+
+    let (raster_files, svg_files): (Vec<PathBuf>, Vec<PathBuf>) = input_files.into_iter()
+        .partition(|path| raster::is_raster(path));
+    println!("Processing {} input SVG files", svg_files.len());
+    if !raster_files.is_empty() {
+        println!("Processing {} input PNG files", raster_files.len());
+    }
+    if !sprite_sources.is_empty() {
+        println!("Merging {} icons from existing sprites", sprite_sources.len());
+    }
+
+    let mut svg_sources: Vec<_> = svg_files.into_iter()
+        .map(|file| SvgSource::load(file,
+                                    inputs.css_override,
+                                    inputs.verbose))
+        .map(|source| source.map(|source| SvgSource {
+            fit: fit::find(inputs.fit_sizes, &source.input_path),
+            ..source
+        }))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut stylesheets: Vec<PathBuf> = svg_sources.iter()
+        .filter_map(|source| source.stylesheet.clone())
+        .collect();
+    stylesheets.sort();
+    stylesheets.dedup();
+    let composites = compose::build_all(&compositions, &svg_sources)?;
+    svg_sources.retain(|source| !part_ids.contains(&source.id));
+    svg_sources.extend(composites);
+    let mut raster_sources = raster::load_rasters(raster_files)?;
+    raster_sources.extend(sprite_sources);
+    let mut sources = AtlasSources::new(svg_sources, raster_sources);
+    sources.check_unique_ids()?;
+    sources.fonts = Arc::new(text::Fonts::load(inputs.font_dirs, inputs.fonts)?);
+    sources.variants = inputs.variants.to_vec();
+    sources.stylesheets = stylesheets;
+    Ok(sources)
+}
+
+fn build(mut args: cli::Config) -> Result<()> {
+    if args.output.file_name().is_none() {
+        bail!("Invalid output file name: {}", pd(&args.output))
+    }
+
     #[cfg(feature = "parallel")]
     {
         println!("Using {} parallel threads", args.threads);
@@ -450,25 +1370,671 @@ fn main() -> Result<()> {
             .build_global()?;
     }
 
-    println!("Processing {} input SVG files", input_files.len());
+    let mut inputs = args.sprite.inputs(&args.svg_dirs, args.verbose);
+    // The atlas images of a previous build are not icons
+    inputs.outputs = ["", "@2x"].iter()
+        .map(|suffix| suffixed_base(&args.output, suffix).with_extension("png"))
+        .chain(args.icons_dir.clone())
+        .collect();
+    let sources = load_sources(&inputs)?;
 
-    let svg_sources: Vec<_> = input_files.into_iter()
-        .map(|file| SvgSource::load(file,
-                                    args.css_override.as_deref(),
-                                    args.verbose))
-        .collect::<Result<Vec<_>, _>>()?;
+    let formats = if args.formats.is_empty() {
+        vec![encode::ImageFormat::Png]
+    } else {
+        args.formats.clone()
+    };
+    for (idx, format) in formats.iter().enumerate() {
+        if let Some(other) = formats[..idx].iter().find(|f| f.extension() == format.extension()) {
+            bail!("{} and {} output would use the same file name", other, format);
+        }
+    }
+    let metadata_formats: Vec<Box<dyn metadata::MetadataFormat>> =
+        if args.metadata_formats.is_empty() {
+            vec![Box::new(metadata::Mapbox)]
+        } else {
+            std::mem::take(&mut args.metadata_formats)
+        };
+    for (idx, format) in metadata_formats.iter().enumerate() {
+        if metadata_formats[..idx].iter().any(|f| f.extension() == format.extension()) {
+            bail!("{} metadata is given more than once", format.name());
+        }
+    }
+    if args.no_atlas && args.icons_dir.is_none() {
+        bail!("Nothing to write: --no-atlas requires --icons-dir");
+    }
+    if args.no_atlas && args.css_sprite.is_some() {
+        bail!("The CSS sprite sheet requires the atlas files");
+    }
+    if args.svg_sprite.is_some() && !sources.rasters.is_empty() {
+        println!("Raster icons are not included in the SVG sprite ({} icons)", sources.rasters.len());
+    }
+    if args.quantize.map_or(false, |quality| quality > 100) {
+        bail!("Invalid quantisation quality, expected 0 to 100");
+    }
+    let encoder_options = encode::EncoderOptions {
+        formats,
+        png: encode::PngOptions {
+            filter: args.png_filter,
+            compression: args.png_compression,
+            quantize: args.quantize,
+        },
+    };
 
-    process(&svg_sources, AtlasOptions::new(&args, 1.0)?,
-            &args.output, args.verbose)?;
+    let load_seed = |suffix: &str| -> Result<Option<LayoutSeed>> {
+        match args.seed_layout {
+            Some(ref base) => LayoutSeed::load(
+                &suffixed_base(base, suffix).with_extension("json"), args.min_fill_ratio),
+            None => Ok(None),
+        }
+    };
+
+    let effects = args.sprite.effects();
+    let atlas_options = |ratio| AtlasOptions::new(args.sprite.buffer.as_ref(), ratio,
+                                                  args.sprite.snap_to_pixels, effects);
+    let mut atlases = vec![process(&sources, atlas_options(1.0)?,
+                                   load_seed("")?.as_ref(), &args.output, args.verbose)?];
 
     if args.with_hires {
-        let mut output_base = args.output.clone();
-        let mut file_name = args.output.file_stem().unwrap().to_owned();
-        file_name.push("@2x");
-        output_base.set_file_name(file_name);
-        process(&svg_sources, AtlasOptions::new(&args, 2.0)?,
-                &output_base, args.verbose)?;
+        let output_base = suffixed_base(&args.output, "@2x");
+        atlases.push(process(&sources, atlas_options(2.0)?,
+                             load_seed("@2x")?.as_ref(), &output_base, args.verbose)?);
+    }
+
+    if args.check {
+        let mut differences = vec![];
+        for atlas in atlases.iter() {
+            if !args.no_atlas {
+                differences.extend(atlas.check(&encoder_options, &metadata_formats)?);
+            }
+            if let Some(ref icons_dir) = args.icons_dir {
+                differences.extend(atlas.check_icons(icons_dir, &encoder_options.png)?);
+            }
+        }
+        for (path, contents) in text_outputs(&args, &sources, &atlases, &encoder_options)? {
+            differences.extend(compare_file(&path, contents.as_bytes()));
+        }
+        for difference in differences.iter() {
+            println!("{}", difference);
+        }
+        if !differences.is_empty() {
+            bail!("Output files are not up to date ({} differences)", differences.len());
+        }
+        println!("Output files are up to date");
+        return Ok(());
+    }
+
+    for atlas in atlases.iter() {
+        if !args.no_atlas {
+            atlas.save(&encoder_options, &metadata_formats)?;
+        }
+        if let Some(ref icons_dir) = args.icons_dir {
+            atlas.save_icons(icons_dir, &encoder_options.png)?;
+        }
+    }
+
+    for (path, contents) in text_outputs(&args, &sources, &atlases, &encoder_options)? {
+        println!("Saving {}", pd(&path));
+        std::fs::write(path, contents)?;
+    }
+
+    if let Some(ref preview_path) = args.preview {
+        let source_paths = sources.ids()
+            .map(|(id, path)| (id.to_owned(), path.to_path_buf()))
+            .collect();
+        preview::write(preview_path, &atlases, &source_paths)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn svg_source(id: &str, width: u32, height: u32) -> SvgSource {
+        let svg = format!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}"><rect x="1" y="1" width="{}" height="{}" fill="#{:06x}"/></svg>"##,
+            width - 2, height - 2, (width * 7919 + height * 104729) & 0xFFFFFF,
+            w = width, h = height);
+        SvgSource {
+            id: id.to_owned(),
+            input_path: PathBuf::from(format!("{}.svg", id)),
+            svg_data: Arc::new(svg.into_bytes()),
+            properties: Default::default(),
+            fit: None,
+            stylesheet: None,
+        }
+    }
+
+    fn build_metadata(sources: &AtlasSources, pixel_ratio: f64) -> serde_json::Value {
+        let options = AtlasOptions {
+            pixel_ratio, buffer_px: 0., snap: None, effects: Default::default(),
+        };
+        PreparedSvgAtlas::new(options, sources, None).unwrap().metadata().unwrap().to_json()
+    }
+
+    fn build_outputs(svgs: Vec<SvgSource>) -> (String, Vec<u8>) {
+        let options = AtlasOptions {
+            pixel_ratio: 1., buffer_px: 1., snap: None, effects: Default::default(),
+        };
+        let atlas = PreparedSvgAtlas::new(options, &AtlasSources::new(svgs, vec![]), None)
+            .unwrap();
+        let metadata = atlas.metadata().unwrap().to_json().to_string();
+        (metadata, encode::png(&atlas.render().unwrap()).unwrap())
+    }
+
+    #[test]
+    fn output_does_not_depend_on_input_order() {
+        // Several icons share the same height to exercise the layout tie-break
+        let sizes = [(12, 12), (16, 12), (20, 24), (8, 12), (24, 24), (10, 8), (12, 16), (16, 16)];
+        let sources = || sizes.iter().enumerate()
+            .map(|(idx, &(w, h))| svg_source(&format!("icon-{}", idx), w, h))
+            .collect::<Vec<_>>();
+        let expected = build_outputs(sources());
+
+        // Deterministic Fisher-Yates shuffles driven by a linear congruential generator
+        let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+        for _ in 0..8 {
+            let mut shuffled = sources();
+            for i in (1..shuffled.len()).rev() {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                shuffled.swap(i, (state >> 33) as usize % (i + 1));
+            }
+            let actual = build_outputs(shuffled);
+            assert_eq!(actual.0, expected.0);
+            assert!(actual.1 == expected.1, "atlas images differ");
+        }
+    }
+
+    #[test]
+    fn merged_sprite_keeps_unknown_fields() {
+        let dir = std::env::temp_dir().join(format!("resprite-merge-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let atlas = create_pixmap(4., 4.).unwrap();
+        std::fs::write(dir.join("vendor.png"), encode::png(&atlas).unwrap()).unwrap();
+        std::fs::write(dir.join("vendor.json"), r#"{"pin": {"width": 4, "height": 4, "x": 0,
+            "y": 0, "pixelRatio": 1, "placement": {"anchor": "bottom"}, "category": "poi"}}"#)
+            .unwrap();
+        let rasters = raster::load_sprite(&dir.join("vendor")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let sources = AtlasSources::new(vec![svg_source("dot", 8, 8)], rasters);
+        let metadata = build_metadata(&sources, 1.);
+        assert_eq!(metadata["pin"]["placement"], serde_json::json!({"anchor": "bottom"}));
+        assert_eq!(metadata["pin"]["category"], "poi");
+        assert_eq!(metadata["pin"]["pixelRatio"], 1.);
+        assert!(metadata["dot"].get("category").is_none());
+    }
+
+    fn layout_seed(layout: &potpack2::Layout, ids: &[String], min_fill_ratio: f64) -> LayoutSeed {
+        let icons = layout.items.iter()
+            .map(|b| (ids[b.id].clone(), sprite::IconMetadata {
+                width: b.w, height: b.h, x: b.x, y: b.y, properties: Default::default(),
+            }))
+            .collect();
+        LayoutSeed { metadata: sprite::SpriteMetadata { icons }, min_fill_ratio }
+    }
+
+    fn assert_no_overlaps(layout: &potpack2::Layout) {
+        for (idx, a) in layout.items.iter().enumerate() {
+            for b in layout.items[idx + 1..].iter() {
+                assert!(a.x + a.w <= b.x || b.x + b.w <= a.x || a.y + a.h <= b.y || b.y + b.h <= a.y,
+                        "{:?} overlaps {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn seeded_layout_keeps_unchanged_icons() {
+        let mut sizes: Vec<(f64, f64)> = (0..24)
+            .map(|i| ((8 + i * 7 % 23) as f64, (6 + i * 11 % 19) as f64))
+            .collect();
+        let mut ids: Vec<String> = (0..sizes.len()).map(|i| format!("icon-{}", i)).collect();
+        let original = layout_atlas(sizes.clone(), 0., &ids, None);
+        let seed = layout_seed(&original, &ids, 0.);
+        let position = |layout: &potpack2::Layout, id: usize| {
+            layout.items.iter().find(|b| b.id == id).map(|b| (b.x, b.y)).unwrap()
+        };
+
+        let unchanged = layout_atlas(sizes.clone(), 0., &ids, Some(&seed));
+        for (idx, id) in ids.iter().enumerate() {
+            assert_eq!(position(&unchanged, idx), position(&original, idx), "{}", id);
+        }
+
+        // Resize one icon and add two new ones
+        sizes[3] = (40., 40.);
+        sizes.extend([(30., 12.), (5., 5.)]);
+        ids.extend(["new-1".to_owned(), "new-2".to_owned()]);
+        let changed = layout_atlas(sizes, 0., &ids, Some(&seed));
+        assert_eq!(changed.items.len(), ids.len());
+        assert_no_overlaps(&changed);
+        for id in (0..24).filter(|&id| id != 3) {
+            assert_eq!(position(&changed, id), position(&original, id), "{}", ids[id]);
+        }
+    }
+
+    #[test]
+    fn sparse_seeded_layout_is_repacked() {
+        let ids = vec!["far".to_owned()];
+        let far = potpack2::Layout {
+            width: 110., height: 110., fill_ratio: 1. / 121.,
+            items: vec![potpack2::Box { id: 0, w: 10., h: 10., x: 100., y: 100. }],
+        };
+        let kept = layout_atlas([(10., 10.)], 0., &ids, Some(&layout_seed(&far, &ids, 0.)));
+        assert_eq!((kept.items[0].x, kept.items[0].y), (100., 100.));
+        let repacked = layout_atlas([(10., 10.)], 0., &ids, Some(&layout_seed(&far, &ids, 0.5)));
+        assert_eq!((repacked.items[0].x, repacked.items[0].y), (0., 0.));
+        assert_eq!(repacked.fill_ratio, 1.);
+    }
+
+    /// Loads an SVG with an image referencing the href, returning the reported problems.
+    fn image_problems(svg_path: &Path, href: &str) -> Vec<String> {
+        let svg = format!(r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="4" height="4"><image width="4" height="4" xlink:href="{}"/></svg>"#, href);
+        let options = AtlasOptions {
+            pixel_ratio: 1., buffer_px: 0., snap: None, effects: Default::default(),
+        };
+        let problems = images::Problems::default();
+        let svg_options = svg_load_options(&options, &Default::default(), svg_path,
+                                           Some(problems.clone()));
+        usvg::Tree::from_data(svg.as_bytes(), &svg_options).unwrap();
+        let result = problems.lock().unwrap().clone();
+        result
+    }
+
+    #[test]
+    fn images_outside_of_the_icon_directory_are_dropped() {
+        let dir = std::env::temp_dir().join(format!("resprite-images-{}", std::process::id()));
+        let icons = dir.join("icons");
+        std::fs::create_dir_all(icons.join("img")).unwrap();
+        let image = r#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="4"><rect width="4" height="4"/></svg>"#;
+        std::fs::write(icons.join("img/inside.svg"), image).unwrap();
+        std::fs::write(dir.join("outside.svg"), image).unwrap();
+        std::fs::write(icons.join("dot.png"), encode::png(&create_pixmap(1., 1.).unwrap()).unwrap())
+            .unwrap();
+        let svg_path = icons.join("icon.svg");
+
+        let outside = Some("the file is outside of the input directory");
+        let not_relative = Some("only relative file paths are allowed");
+        let raster = if cfg!(feature = "raster-images") {
+            None
+        } else {
+            Some("raster images require the raster-images feature")
+        };
+        let absolute = icons.join("img/inside.svg").to_string_lossy().into_owned();
+        let mut cases = vec![
+            ("img/inside.svg", None),
+            ("img/../img/inside.svg", None),
+            ("../outside.svg", outside),
+            ("img/../../outside.svg", outside),
+            (absolute.as_str(), not_relative),
+            ("file:img/inside.svg", not_relative),
+            ("file:///etc/hosts", not_relative),
+            ("http://example.com/img/inside.svg", not_relative),
+            ("img/missing.svg", Some("file not found")),
+            ("dot.png", raster),
+            ("data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk+M9QDwADhgGAWjR9awAAAABJRU5ErkJggg==",
+             raster),
+        ];
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("../../outside.svg", icons.join("img/link.svg")).unwrap();
+            cases.push(("img/link.svg", outside));
+        }
+        let results: Vec<_> = cases.iter()
+            .map(|&(href, _)| image_problems(&svg_path, href))
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        for ((href, reason), problems) in cases.iter().zip(results) {
+            match reason {
+                Some(reason) => {
+                    assert_eq!(problems.len(), 1, "{}", href);
+                    assert!(problems[0].starts_with("dropped image ")
+                            && problems[0].ends_with(reason), "{}: {}", href, problems[0]);
+                },
+                None => assert!(problems.is_empty(), "{}: {:?}", href, problems),
+            }
+        }
+    }
+
+    #[test]
+    fn extracted_icons_keep_unknown_fields() {
+        let dir = std::env::temp_dir().join(format!("resprite-extract-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let atlas = create_pixmap(4., 4.).unwrap();
+        std::fs::write(dir.join("vendor.png"), encode::png(&atlas).unwrap()).unwrap();
+        std::fs::write(dir.join("vendor.json"), r#"{"pin": {"width": 4, "height": 4, "x": 0,
+            "y": 0, "pixelRatio": 1, "placement": {"anchor": "bottom"}, "category": "poi"}}"#)
+            .unwrap();
+        extract::run(&dir.join("vendor.json"), &dir.join("vendor.png"), &dir.join("icons"))
+            .unwrap();
+        let rasters = raster::load_rasters(vec![dir.join("icons/pin.png")]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut svg = svg_source("dot", 8, 8);
+        svg.properties = sprite::IconProperties::from_json(
+            &serde_json::json!({"pixelRatio": 1, "category": "shop"})).unwrap();
+        let metadata = build_metadata(&AtlasSources::new(vec![svg], rasters), 1.);
+        assert_eq!(metadata["pin"]["placement"], serde_json::json!({"anchor": "bottom"}));
+        assert_eq!(metadata["pin"]["category"], "poi");
+        assert_eq!(metadata["dot"]["category"], "shop");
+    }
+
+    #[test]
+    fn embedded_icons_only_rename_id_links() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="8" height="8" viewBox="0 0 8 8"><defs><path id="fff" d="M0 0h4v4z"/></defs><use xlink:href="#fff" fill="#fff"/><use href="#fff" stroke="url(#fff)"/></svg>"##;
+        let source = SvgSource {
+            svg_data: Arc::new(svg.as_bytes().to_vec()),
+            ..svg_source("dot", 8, 8)
+        };
+        let (root, _) = symbols::embeddable_root(&source).unwrap();
+        let uses: Vec<_> = root.children.iter()
+            .filter_map(|node| node.as_element())
+            .filter(|element| element.name == "use")
+            .map(|element| &element.attributes)
+            .collect();
+        assert_eq!(uses[0]["href"], "#dot__fff");
+        assert_eq!(uses[0]["fill"], "#fff");
+        assert_eq!(uses[1]["href"], "#dot__fff");
+        assert_eq!(uses[1]["stroke"], "url(#dot__fff)");
+    }
+
+    #[test]
+    fn loaded_sources_list_their_stylesheets() {
+        let dir = std::env::temp_dir().join(format!("resprite-css-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("css")).unwrap();
+        std::fs::write(dir.join("css/icons.css"), "rect { fill: red }").unwrap();
+        std::fs::write(dir.join("styled.svg"), r#"<?xml-stylesheet type="text/css" href="css/icons.css"?><svg xmlns="http://www.w3.org/2000/svg" width="4" height="4"><rect width="4" height="4"/></svg>"#)
+            .unwrap();
+        std::fs::write(dir.join("plain.svg"), r#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="4"/>"#)
+            .unwrap();
+        let input_paths = [dir.clone()];
+        let inputs = InputOptions {
+            input_paths: &input_paths, css_override: None, sprites: &[], style: None,
+            font_dirs: &[], fonts: &[], fit_sizes: &[], compose: None, variants: &[],
+            outputs: vec![], verbose: false,
+        };
+        let sources = load_sources(&inputs).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(sources.stylesheets, [dir.join("css/icons.css")]);
+    }
+
+    #[test]
+    fn sidecar_content_follows_the_view_box_origin() {
+        let source = SvgSource {
+            svg_data: Arc::new(br#"<svg xmlns="http://www.w3.org/2000/svg" width="12" height="14" viewBox="10 10 12 14"><rect x="12.5" y="12" width="8" height="10"/></svg>"#.to_vec()),
+            properties: sprite::IconProperties {
+                content: Some([12., 12., 20., 22.]),
+                ..Default::default()
+            },
+            ..svg_source("shifted", 12, 14)
+        };
+        let sources = AtlasSources::new(vec![source], vec![]);
+        let metadata = build_metadata(&sources, 1.);
+        assert_eq!(metadata["shifted"]["content"], serde_json::json!([2., 2., 10., 12.]));
+
+        // Snapping moves the drawing from 2.5 to 3 pixels, and the content box with it
+        let options = AtlasOptions {
+            pixel_ratio: 1., buffer_px: 0., snap: Some(snap::SnapMode::Align),
+            effects: Default::default(),
+        };
+        let metadata = PreparedSvgAtlas::new(options, &sources, None).unwrap()
+            .metadata().unwrap().to_json();
+        assert_eq!(metadata["shifted"]["content"], serde_json::json!([2.5, 2., 10.5, 12.]));
+    }
+
+    #[test]
+    fn fitted_icons_match_unfitted_ones_at_2x() {
+        let fitted = SvgSource {
+            fit: Some(fit::FitSize::Width(24.)),
+            ..svg_source("fitted", 12, 13)
+        };
+        let sources = AtlasSources::new(vec![fitted, svg_source("unfitted", 24, 26)], vec![]);
+        let metadata = build_metadata(&sources, 2.);
+        for id in ["fitted", "unfitted"] {
+            assert_eq!((metadata[id]["width"].as_f64(), metadata[id]["height"].as_f64()),
+                       (Some(24.), Some(26.)), "size of {}", id);
+        }
+    }
+
+    #[cfg(feature = "text")]
+    #[test]
+    fn text_is_rendered_with_given_fonts() {
+        let fonts_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fonts");
+        let fonts = text::Fonts::load(&[fonts_dir], &[]).unwrap();
+        assert_eq!(fonts.default_family.as_deref(), Some("Block"));
+        // The "A" glyph of the Block font is a square from (1, 0) to (9, 8) here
+        let text_icon = |id: &str, family: &str| SvgSource {
+            svg_data: Arc::new(format!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><text x="0" y="8" font-family="{}" font-size="10">A</text></svg>"#,
+                family).into_bytes()),
+            ..svg_source(id, 10, 10)
+        };
+        let mut sources = AtlasSources::new(
+            vec![text_icon("named", "Block"), text_icon("fallback", "serif")], vec![]);
+        sources.fonts = Arc::new(fonts);
+        let options = AtlasOptions {
+            pixel_ratio: 1., buffer_px: 0., snap: None, effects: Default::default(),
+        };
+        let atlas = PreparedSvgAtlas::new(options, &sources, None).unwrap();
+        let metadata = atlas.metadata().unwrap();
+        let pixmap = atlas.render().unwrap();
+        for id in ["named", "fallback"] {
+            let icon = &metadata.icons[id];
+            let alpha = |x: f64, y: f64| pixmap.pixel((icon.x + x) as u32, (icon.y + y) as u32)
+                .unwrap().alpha();
+            assert_eq!(alpha(5., 4.), 255, "inside the glyph of {}", id);
+            assert_eq!(alpha(5., 9.), 0, "below the glyph of {}", id);
+            assert_eq!(alpha(0., 4.), 0, "left of the glyph of {}", id);
+        }
+    }
+
+    fn filtered_pixel(variant: &str, rgba: [u8; 4]) -> [u8; 4] {
+        let variant: variants::Variant = variant.parse().unwrap();
+        let mut pixmap = create_pixmap(1., 1.).unwrap();
+        let [r, g, b, a] = rgba;
+        pixmap.pixels_mut()[0] = resvg::tiny_skia::ColorU8::from_rgba(r, g, b, a).premultiply();
+        variant.apply(&mut pixmap);
+        let color = pixmap.pixels()[0].demultiply();
+        [color.red(), color.green(), color.blue(), color.alpha()]
+    }
+
+    #[test]
+    fn variant_with_leading_dash_suffix() {
+        let args: &[&str] = &["--variant=-inactive:greyscale opacity(50%)",
+                              "--variant", "poi=-hover:tint(#0066ff)", "-o", "sprite.png", "icons"];
+        let config = match cli::command_parser().run_inner(bpaf::Args::from(args)) {
+            Ok(cli::Command::Build(config)) => config,
+            _ => panic!("--variant arguments are not parsed"),
+        };
+        let suffixes: Vec<&str> = config.sprite.variants.iter().map(|v| v.suffix.as_str()).collect();
+        assert_eq!(suffixes, ["-inactive", "-hover"]);
+    }
+
+    #[test]
+    fn variant_matrix_with_spaces() {
+        // Swaps the red and blue channels
+        let variant = "swapped:matrix(0 0 1 0 0  0 1 0 0 0  1 0 0 0 0  0 0 0 1 0)";
+        assert_eq!(filtered_pixel(variant, [255, 128, 0, 255]), [0, 128, 255, 255]);
+    }
+
+    #[test]
+    fn variant_tint_with_rgb_color() {
+        let variant = "tinted:greyscale tint(rgb(0, 102, 255)) opacity(50%)";
+        assert_eq!(filtered_pixel(variant, [255, 255, 255, 255]), [0, 102, 255, 128]);
+    }
+
+    /// Returns a pixmap whose pixels are picked from the premultiplied colors
+    /// in a pattern which does not compress too well.
+    fn patterned_pixmap(width: u32, height: u32, colors: &[[u8; 4]])
+                        -> resvg::tiny_skia::Pixmap {
+        let mut pixmap = resvg::tiny_skia::Pixmap::new(width, height).unwrap();
+        for (idx, pixel) in pixmap.pixels_mut().iter_mut().enumerate() {
+            let hash = (idx as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            let hash = (hash ^ hash >> 29).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            let [r, g, b, a] = colors[(hash >> 40) as usize % colors.len()];
+            *pixel = resvg::tiny_skia::PremultipliedColorU8::from_rgba(r, g, b, a).unwrap();
+        }
+        pixmap
+    }
+
+    /// Decodes the PNG to straight RGBA, returning its color type, bit depth and tRNS length.
+    fn decode_png(data: &[u8]) -> (png::ColorType, png::BitDepth, usize, Vec<u8>) {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut pixels).unwrap();
+        pixels.truncate(frame.buffer_size());
+        let info = reader.info();
+        let trns = info.trns.as_ref().map_or(0, |t| t.len());
+        let pixels = match frame.color_type {
+            png::ColorType::Rgba => pixels,
+            png::ColorType::Rgb => {
+                pixels.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect()
+            },
+            png::ColorType::GrayscaleAlpha => {
+                pixels.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect()
+            },
+            png::ColorType::Grayscale => pixels.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+            png::ColorType::Indexed => unreachable!("palette is expanded"),
+        };
+        (info.color_type, info.bit_depth, trns, pixels)
+    }
+
+    fn lossless_png() -> encode::PngOptions {
+        encode::PngOptions {
+            filter: encode::PngFilter::Auto,
+            compression: encode::PngCompression::Best,
+            quantize: None,
+        }
+    }
+
+    #[test]
+    fn palette_pngs_round_trip_at_each_bit_depth() {
+        // Odd widths leave the last byte of each row partly used
+        for (color_count, depth) in [(2, png::BitDepth::One), (4, png::BitDepth::Two),
+                                     (16, png::BitDepth::Four), (200, png::BitDepth::Eight)] {
+            let colors: Vec<[u8; 4]> = (0..color_count)
+                .map(|i| [(i * 37 % 256) as u8, (i * 91 % 256) as u8, (i * 13) as u8, 255])
+                .collect();
+            let pixmap = patterned_pixmap(101, 67, &colors);
+            let encoded = encode::optimized_png(&pixmap, &lossless_png()).unwrap();
+            let (color, bit_depth, _, pixels) = decode_png(&encoded.data);
+            assert_eq!((color, bit_depth), (png::ColorType::Indexed, depth),
+                       "{} colors", color_count);
+            assert_eq!(pixels, encode::demultiplied_rgba(&pixmap), "{} colors", color_count);
+        }
+    }
+
+    #[test]
+    fn palette_png_lists_translucent_colors_first() {
+        let colors = [[0, 0, 0, 0], [200, 10, 10, 255], [60, 60, 0, 128], [0, 20, 250, 255],
+                      [10, 10, 10, 10], [5, 250, 5, 255]];
+        let pixmap = patterned_pixmap(101, 67, &colors);
+        let encoded = encode::optimized_png(&pixmap, &lossless_png()).unwrap();
+        let (color, _, trns, pixels) = decode_png(&encoded.data);
+        assert_eq!(color, png::ColorType::Indexed);
+        assert_eq!(trns, 3);
+        assert_eq!(pixels, encode::demultiplied_rgba(&pixmap));
+    }
+
+    #[test]
+    fn optimized_png_is_lossless_and_not_larger_than_plain() {
+        let colors: Vec<[u8; 4]> = (0..300u32)
+            .map(|i| [(i % 256) as u8, (i * 3 % 256) as u8, (i * 7 % 256) as u8, 255])
+            .collect();
+        for pixmap in [patterned_pixmap(37, 23, &colors), patterned_pixmap(1, 1, &colors[..1])] {
+            let encoded = encode::optimized_png(&pixmap, &lossless_png()).unwrap();
+            assert!(encoded.data.len() <= encode::png(&pixmap).unwrap().len());
+            assert_eq!(decode_png(&encoded.data).3, encode::demultiplied_rgba(&pixmap));
+        }
+    }
+
+    #[test]
+    fn quantized_png_is_close_to_the_original() {
+        let colors: Vec<[u8; 4]> = (0..1000u32)
+            .map(|i| [(i % 256) as u8, (i / 4 % 256) as u8, 128, 255])
+            .collect();
+        let pixmap = patterned_pixmap(128, 128, &colors);
+        let options = encode::PngOptions { quantize: Some(0), ..lossless_png() };
+        let encoded = encode::optimized_png(&pixmap, &options).unwrap();
+        assert!(encoded.description.contains("lossy"), "{}", encoded.description);
+        let (color, _, _, pixels) = decode_png(&encoded.data);
+        assert_eq!(color, png::ColorType::Indexed);
+        let original = encode::demultiplied_rgba(&pixmap);
+        let max_error = pixels.iter().zip(&original)
+            .map(|(&a, &b)| (a as i32 - b as i32).abs())
+            .max().unwrap();
+        assert!(max_error <= 16, "maximum channel error {}", max_error);
+    }
+
+    /// Returns the image name patterns of a style with a single `icon-image` value.
+    fn icon_image_patterns(value: serde_json::Value) -> Vec<style::IconReference> {
+        let style = serde_json::json!({
+            "layers": [{"id": "icons", "type": "symbol", "layout": {"icon-image": value}}],
+        });
+        style::collect_references(&style).unwrap()
+    }
+
+    #[test]
+    fn style_expressions_produce_image_patterns() {
+        use serde_json::json;
+        let cases = [
+            (json!("airport-{size}"), vec!["airport-*"]),
+            (json!("{class}-{rank}"), vec!["*-*"]),
+            (json!("open-{brace"), vec!["open-{brace"]),
+            (json!(""), vec![]),
+            (json!(["get", "icon"]), vec!["*"]),
+            (json!(["literal", "pin"]), vec!["pin"]),
+            (json!(["image", ["concat", "poi-", ["get", "class"]]]), vec!["poi-*"]),
+            (json!(["match", ["get", "class"], "rail", "train", ["bus", "tram"], "bus", "dot"]),
+             vec!["train", "bus", "dot"]),
+            (json!(["case", ["has", "ref"], "shield", ["==", ["get", "n"], 1], "one", "plain"]),
+             vec!["shield", "one", "plain"]),
+            (json!(["step", ["zoom"], "small", 10, "medium", 14, "large"]),
+             vec!["small", "medium", "large"]),
+            (json!(["coalesce", ["image", ["get", "icon"]], "fallback"]), vec!["*", "fallback"]),
+            (json!(["concat", "shield-", ["get", "network"], "-", ["to-string", ["get", "len"]]]),
+             vec!["shield-*-*"]),
+            (json!(["concat", ["match", ["get", "a"], "x", "a", "b"], "-",
+                    ["step", ["zoom"], "1", 5, "2"]]),
+             vec!["a-1", "a-2", "b-1", "b-2"]),
+            (json!(["let", "n", 1, ["case", ["var", "n"], "yes", "no"]]), vec!["yes", "no"]),
+            (json!({"stops": [[0, "small-{class}"], [10, "large"]], "default": "dot"}),
+             vec!["small-*", "large", "dot"]),
+            (json!({"type": "identity", "property": "icon"}), vec!["*"]),
+        ];
+        for (value, expected) in cases {
+            let patterns: Vec<String> = icon_image_patterns(value.clone()).iter()
+                .map(|r| r.to_string())
+                .collect();
+            let expected: Vec<String> = expected.iter()
+                .map(|p| format!("layer 'icons' icon-image '{}'", p))
+                .collect();
+            assert_eq!(patterns, expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn style_patterns_match_icon_ids() {
+        let cases = [
+            ("airport-{size}", "airport-11", true),
+            ("airport-{size}", "airport-", true),
+            ("airport-{size}", "airport", false),
+            ("airport-{size}", "heliport-11", false),
+            ("{class}-{rank}", "a-b-c", true),
+            ("{class}-{rank}", "abc", false),
+            ("shield-{n}-wide", "shield-3-wide", true),
+            ("shield-{n}-wide", "shield-3-wider", false),
+            ("{a}{b}x", "x", true),
+            ("caf{x}-é", "café-é", true),
+            ("plain", "plain", true),
+            ("plain", "plain-2", false),
+        ];
+        for (template, id, expected) in cases {
+            let references = icon_image_patterns(serde_json::json!(template));
+            assert_eq!(style::is_referenced(&references, id), expected, "{} ~ {}", template, id);
+        }
+    }
+}
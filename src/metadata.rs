@@ -0,0 +1,206 @@
+//! Atlas metadata file formats.
+//!
+//! Besides the Mapbox sprite JSON, the layout can be written in formats read by
+//! generic texture atlas loaders. Icon rectangles include the buffer in all of them.
+
+use std::fmt::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::pd;
+use crate::preview::escape_html;
+use crate::sprite::SpriteMetadata;
+
+/// An atlas as seen by a metadata file.
+pub struct AtlasInfo<'a> {
+    /// File name of the atlas image, relative to the metadata file
+    pub image_file: String,
+    pub width: u32,
+    pub height: u32,
+    pub pixel_ratio: f64,
+    pub metadata: &'a SpriteMetadata,
+}
+
+pub trait MetadataFormat {
+    /// Returns the name used on the command line.
+    fn name(&self) -> &'static str;
+
+    /// Returns the extension of the metadata file (replacing the one of the output path).
+    fn extension(&self) -> &'static str;
+
+    fn generate(&self, atlas: &AtlasInfo) -> String;
+
+    /// Compares the atlas with an existing metadata file, returning the differences.
+    fn check(&self, path: &Path, atlas: &AtlasInfo) -> Vec<String> {
+        crate::compare_file(path, self.generate(atlas).as_bytes()).into_iter().collect()
+    }
+}
+
+impl FromStr for Box<dyn MetadataFormat> {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "mapbox" => Box::new(Mapbox),
+            "texturepacker-hash" => Box::new(TexturePacker { array: false }),
+            "texturepacker-array" => Box::new(TexturePacker { array: true }),
+            "libgdx" => Box::new(LibGdx),
+            "starling" | "sparrow" => Box::new(Starling),
+            _ => return Err(format!(
+                "Unknown metadata format {:?}, expected mapbox, texturepacker-hash, \
+                 texturepacker-array, libgdx or starling", s)),
+        })
+    }
+}
+
+/// Mapbox/MapLibre sprite JSON.
+pub struct Mapbox;
+
+impl MetadataFormat for Mapbox {
+    fn name(&self) -> &'static str {
+        "mapbox"
+    }
+
+    fn extension(&self) -> &'static str {
+        "json"
+    }
+
+    fn generate(&self, atlas: &AtlasInfo) -> String {
+        // JSON object keys are always sorted (serde_json is used without preserve_order)
+        atlas.metadata.to_json().to_string()
+    }
+
+    /// Compares the icons one by one, so that the differences are reported per icon.
+    fn check(&self, path: &Path, atlas: &AtlasInfo) -> Vec<String> {
+        let mut result = vec![];
+        match SpriteMetadata::load(path) {
+            Ok(existing) => {
+                let ids: std::collections::BTreeSet<&String> = existing.icons.keys()
+                    .chain(atlas.metadata.icons.keys()).collect();
+                for id in ids {
+                    let message = match (existing.icons.get(id), atlas.metadata.icons.get(id)) {
+                        (None, _) => "is missing",
+                        (_, None) => "is no longer built",
+                        (Some(a), Some(b)) if a != b => "differs",
+                        _ => continue,
+                    };
+                    result.push(format!("{}: icon {} {}", pd(path), id, message));
+                }
+            },
+            Err(e) => result.push(format!("{}: {}", pd(path), e)),
+        }
+        result
+    }
+}
+
+/// Returns a JSON number, without a fraction for whole pixels (as loaders may expect integers).
+fn pixels(value: f64) -> Value {
+    if value.fract() == 0. && value.abs() < u32::MAX as f64 {
+        json!(value as i64)
+    } else {
+        json!(value)
+    }
+}
+
+/// TexturePacker JSON, with the frames as an object keyed by ID or as an array.
+pub struct TexturePacker {
+    array: bool,
+}
+
+impl MetadataFormat for TexturePacker {
+    fn name(&self) -> &'static str {
+        if self.array { "texturepacker-array" } else { "texturepacker-hash" }
+    }
+
+    fn extension(&self) -> &'static str {
+        if self.array { "array.json" } else { "hash.json" }
+    }
+
+    fn generate(&self, atlas: &AtlasInfo) -> String {
+        let frames = atlas.metadata.icons.iter().map(|(id, icon)| {
+            let (w, h) = (pixels(icon.width), pixels(icon.height));
+            (id, json!({
+                "frame": { "x": pixels(icon.x), "y": pixels(icon.y), "w": w, "h": h },
+                "rotated": false,
+                "trimmed": false,
+                "spriteSourceSize": { "x": 0, "y": 0, "w": w, "h": h },
+                "sourceSize": { "w": w, "h": h },
+            }))
+        });
+        let frames = if self.array {
+            Value::Array(frames.map(|(id, mut frame)| {
+                frame["filename"] = json!(id);
+                frame
+            }).collect())
+        } else {
+            Value::Object(frames.map(|(id, frame)| (id.clone(), frame)).collect())
+        };
+        json!({
+            "frames": frames,
+            "meta": {
+                "app": "resprite",
+                "version": env!("CARGO_PKG_VERSION"),
+                "image": atlas.image_file,
+                "format": "RGBA8888",
+                "size": { "w": atlas.width, "h": atlas.height },
+                "scale": atlas.pixel_ratio.to_string(),
+            },
+        }).to_string()
+    }
+}
+
+/// libGDX texture atlas (`.atlas`).
+pub struct LibGdx;
+
+impl MetadataFormat for LibGdx {
+    fn name(&self) -> &'static str {
+        "libgdx"
+    }
+
+    fn extension(&self) -> &'static str {
+        "atlas"
+    }
+
+    fn generate(&self, atlas: &AtlasInfo) -> String {
+        let mut result = String::new();
+        let _ = writeln!(result, "\n{}", atlas.image_file);
+        let _ = writeln!(result, "size: {}, {}", atlas.width, atlas.height);
+        result.push_str("format: RGBA8888\nfilter: Linear, Linear\nrepeat: none\n");
+        for (id, icon) in atlas.metadata.icons.iter() {
+            let _ = writeln!(result, "{}", id);
+            result.push_str("  rotate: false\n");
+            let _ = writeln!(result, "  xy: {}, {}", icon.x, icon.y);
+            let _ = writeln!(result, "  size: {}, {}", icon.width, icon.height);
+            let _ = writeln!(result, "  orig: {}, {}", icon.width, icon.height);
+            result.push_str("  offset: 0, 0\n  index: -1\n");
+        }
+        result
+    }
+}
+
+/// Starling/Sparrow texture atlas XML.
+pub struct Starling;
+
+impl MetadataFormat for Starling {
+    fn name(&self) -> &'static str {
+        "starling"
+    }
+
+    fn extension(&self) -> &'static str {
+        "xml"
+    }
+
+    fn generate(&self, atlas: &AtlasInfo) -> String {
+        let mut result = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(result, "<TextureAtlas imagePath=\"{}\">", escape_html(&atlas.image_file));
+        for (id, icon) in atlas.metadata.icons.iter() {
+            let _ = writeln!(result, "  <SubTexture name=\"{}\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>",
+                             escape_html(id), icon.x, icon.y, icon.width, icon.height);
+        }
+        result.push_str("</TextureAtlas>\n");
+        result
+    }
+}
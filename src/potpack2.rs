@@ -39,25 +39,173 @@ pub struct Layout {
     pub items: Vec<Box>,
 }
 
+impl Box {
+    fn overlaps(&self, other: &Box) -> bool {
+        self.x < other.x + other.w && other.x < self.x + self.w
+            && self.y < other.y + other.h && other.y < self.y + self.h
+    }
+}
+
+fn compare_for_insertion(a: &Box, b: &Box) -> std::cmp::Ordering {
+    // by height, descending;
+    // ties are broken by id to keep the layout reproducible
+    b.h.partial_cmp(&a.h).unwrap().then(a.id.cmp(&b.id))
+}
+
+/// Orders positions from the top and then from the left.
+fn compare_corners(a: (f64, f64), b: (f64, f64)) -> std::cmp::Ordering {
+    (a.1, a.0).partial_cmp(&(b.1, b.0)).unwrap()
+}
+
+/// Placed boxes, indexed by a grid of cells for the overlap tests.
+struct PlacedBoxes {
+    boxes: Vec<Box>,
+    cell_size: f64,
+    cells: std::collections::HashMap<(i64, i64), Vec<usize>>,
+}
+
+impl PlacedBoxes {
+    fn new(boxes: &[Box]) -> Self {
+        // cells of the average box size
+        let total_area: f64 = boxes.iter().map(|b| b.h * b.w).sum();
+        let cell_size = (total_area / boxes.len().max(1) as f64).sqrt().max(1.);
+        Self { boxes: vec![], cell_size, cells: Default::default() }
+    }
+
+    /// Returns the range of the cells covering a span.
+    fn cell_range(&self, start: f64, size: f64) -> std::ops::RangeInclusive<i64> {
+        // The following casts are saturating
+        let first = (start / self.cell_size).floor() as i64;
+        let last = ((start + size) / self.cell_size).ceil() as i64 - 1;
+        first..=last.max(first)
+    }
+
+    fn push(&mut self, b: Box) {
+        for cx in self.cell_range(b.x, b.w) {
+            for cy in self.cell_range(b.y, b.h) {
+                self.cells.entry((cx, cy)).or_default().push(self.boxes.len());
+            }
+        }
+        self.boxes.push(b);
+    }
+
+    fn overlaps(&self, b: &Box) -> bool {
+        self.cell_range(b.x, b.w).any(|cx| self.cell_range(b.y, b.h).any(|cy| {
+            self.cells.get(&(cx, cy))
+                .map_or(false, |idxs| idxs.iter().any(|&idx| self.boxes[idx].overlaps(b)))
+        }))
+    }
+
+    /// Returns `true` if no box can be placed at a position.
+    fn covers(&self, (x, y): (f64, f64)) -> bool {
+        let cell = ((x / self.cell_size).floor() as i64, (y / self.cell_size).floor() as i64);
+        self.cells.get(&cell).map_or(false, |idxs| idxs.iter().any(|&idx| {
+            let p = &self.boxes[idx];
+            p.x <= x && x < p.x + p.w && p.y <= y && y < p.y + p.h
+        }))
+    }
+}
+
+fn unplaced_boxes<I: IntoIterator<Item=impl Rect>>(items: I) -> Vec<Box> {
+    items.into_iter().enumerate()
+        .map(|(idx, item)| Box {
+            id: idx,
+            w: item.width(),
+            h: item.height(),
+            x: f64::NAN,
+            y: f64::NAN,
+        } ).collect()
+}
+
 impl Layout {
     pub fn new<I: IntoIterator<Item=impl Rect>>(items: I) -> Self {
-        let boxes: Vec<_> = items.into_iter().enumerate()
-            .map(|(idx, item)| Box {
-                id: idx,
-                w: item.width(),
-                h: item.height(),
-                x: f64::NAN,
-                y: f64::NAN,
-            } ).collect();
-        Self::from_boxes(boxes)
+        Self::from_boxes(unplaced_boxes(items))
+    }
+
+    /// Creates a layout keeping the given positions of the items where possible.
+    ///
+    /// Items without a position, and items overlapping an already kept one,
+    /// are placed into the free space left by the kept items, as high and
+    /// then as far left as possible.
+    pub fn seeded<I: IntoIterator<Item=impl Rect>>(
+        items: I, positions: &[Option<(f64, f64)>]) -> Self {
+        let boxes = unplaced_boxes(items);
+        let total_area: f64 = boxes.iter().map(|b| b.h * b.w).sum();
+
+        let mut placed = PlacedBoxes::new(&boxes);
+        let mut pending: Vec<Box> = vec![];
+        for b in boxes {
+            if let Some(&Some((x, y))) = positions.get(b.id) {
+                let kept = Box { x, y, ..b };
+                if x >= 0. && y >= 0. && !placed.overlaps(&kept) {
+                    placed.push(kept);
+                    continue;
+                }
+            }
+            pending.push(b);
+        }
+        if placed.boxes.is_empty() {
+            return Self::from_boxes(pending);
+        }
+
+        let mut width = placed.boxes.iter().map(|b| b.x + b.w).fold(0., f64::max);
+        let mut height = placed.boxes.iter().map(|b| b.y + b.h).fold(0., f64::max);
+        // new boxes may widen the container up to a squarish shape
+        let max_width = pending.iter().map(|b| b.w).fold(width, f64::max);
+        let bound = (total_area / 0.95).sqrt().ceil().max(max_width);
+
+        // candidate positions are the top-left corner, the bottom left corner
+        // of the container, and the free corners next to the placed boxes,
+        // ordered from the top and then from the left
+        let mut corners = vec![(0., 0.)];
+        for p in placed.boxes.iter() {
+            corners.extend([(p.x + p.w, p.y), (p.x, p.y + p.h)]);
+        }
+        corners.retain(|&corner| !placed.covers(corner));
+        corners.sort_by(|a, b| compare_corners(*a, *b));
+        corners.dedup();
+
+        pending.sort_by(compare_for_insertion);
+        for mut b in pending {
+            let best = corners.iter()
+                .take_while(|&&(x, y)| (y, x) < (height, 0.))
+                .find(|&&(x, y)| x + b.w <= bound && !placed.overlaps(&Box { x, y, ..b }));
+            // the bottom left corner of the container is always free
+            let (x, y) = best.copied().unwrap_or((0., height));
+            b.x = x;
+            b.y = y;
+            width = width.max(b.x + b.w);
+            height = height.max(b.y + b.h);
+
+            corners.retain(|&(x, y)| !(b.x <= x && x < b.x + b.w && b.y <= y && y < b.y + b.h));
+            for corner in [(b.x + b.w, b.y), (b.x, b.y + b.h)] {
+                if let Err(idx) = corners.binary_search_by(|c| compare_corners(*c, corner)) {
+                    if !placed.covers(corner) {
+                        corners.insert(idx, corner);
+                    }
+                }
+            }
+            placed.push(b);
+        }
+
+        let fill_ratio = if width != 0. && height != 0. {
+                total_area / (width * height)
+            } else { 1. };
+
+        Self {
+            width,
+            height,
+            fill_ratio,
+            items: placed.boxes,
+        }
     }
 
     fn from_boxes(mut boxes: Vec<Box>) -> Self {
         let total_area: f64 = boxes.iter().map(|b| b.h * b.w).sum();
         let max_width = boxes.iter().map(|b| b.w)
             .fold(f64::NEG_INFINITY, f64::max);
-        // sort the boxes for insertion by height, descending
-        boxes.sort_unstable_by(|a, b| b.h.partial_cmp(&a.h).unwrap());
+        // sort the boxes for insertion
+        boxes.sort_by(compare_for_insertion);
         // aim for a squarish resulting container,
         // slightly adjusted for sub-100% space utilization
         let start_width = (total_area / 0.95).sqrt().ceil().max(max_width);
@@ -70,7 +218,7 @@ impl Layout {
         let mut width: f64 = 0.;
         let mut height: f64 = 0.;
 
-        for mut b in boxes.iter_mut() {
+        for b in boxes.iter_mut() {
             // look through spaces backwards so that we check smaller spaces first
             for (space_idx, space) in spaces.iter_mut().enumerate().rev() {
                 // look for empty spaces that can accommodate the current box
@@ -0,0 +1,164 @@
+//! Self-contained HTML preview (contact sheet) of the built atlases.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::sprite::{IconMetadata, SpriteMetadata};
+use crate::{encode, pd, BuiltAtlas};
+
+/// Zoom factor of the icon grid, in CSS pixels per icon pixel at 1x
+const GRID_ZOOM: f64 = 2.;
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 1em; background: #fff; color: #222; }
+body.dark { background: #222; color: #ddd; }
+.atlas { position: relative; display: inline-block; outline: 1px dashed #888; }
+.atlas img { display: block; }
+.atlas .box { position: absolute; box-sizing: border-box; }
+.atlas .box:hover { outline: 1px solid #e00; background: rgba(255, 0, 0, 0.15); }
+.grid { display: flex; flex-wrap: wrap; gap: 12px; }
+.icon { border: 1px solid #8884; padding: 8px; font-size: 12px; max-width: 240px; }
+.icon .image { position: relative; background-repeat: no-repeat; margin-bottom: 6px;
+  image-rendering: pixelated; outline: 1px dotted #8888; }
+.icon .stretch-x, .icon .stretch-y { position: absolute; background: rgba(0, 128, 255, 0.3); }
+.icon .content { position: absolute; box-sizing: border-box; border: 1px solid #0a0; }
+.icon .id { font-weight: bold; word-break: break-all; }
+.icon .path { color: #888; word-break: break-all; }
+"#;
+
+const SCRIPT: &str = r#"
+function toggleBackground() { document.body.classList.toggle('dark'); }
+"#;
+
+pub fn escape_html(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+pub fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut result = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                result.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                result.push('=');
+            }
+        }
+    }
+    result
+}
+
+fn ratio_label(pixel_ratio: f64) -> String {
+    format!("@{}x", pixel_ratio)
+}
+
+fn write_atlas(html: &mut String, atlas: &BuiltAtlas, image_url: &str) {
+    let ratio = atlas.pixel_ratio;
+    let _ = writeln!(html, "<h2>{} &mdash; {}x{} px</h2>",
+                     ratio_label(ratio), atlas.image.width(), atlas.image.height());
+    let _ = writeln!(html, r#"<div class="atlas"><img src="{}" width="{}" height="{}" alt="">"#,
+                     image_url, atlas.image.width() as f64 / ratio,
+                     atlas.image.height() as f64 / ratio);
+    for (id, icon) in atlas.metadata.icons.iter() {
+        let _ = writeln!(
+            html,
+            r#"<div class="box" style="left:{}px;top:{}px;width:{}px;height:{}px" title="{} ({}x{})"></div>"#,
+            icon.x / ratio, icon.y / ratio, icon.width / ratio, icon.height / ratio,
+            escape_html(id), icon.width, icon.height);
+    }
+    html.push_str("</div>\n");
+}
+
+fn write_icon(html: &mut String, id: &str, icon: &IconMetadata, atlas: &BuiltAtlas,
+              source_path: Option<&Path>) {
+    let ratio = atlas.pixel_ratio;
+    // Icon pixels to grid CSS pixels
+    let scale = GRID_ZOOM / ratio;
+    let props = &icon.properties;
+    let _ = writeln!(
+        html,
+        r#"<div class="icon"><div class="image grid-image" style="width:{}px;height:{}px;background-position:-{}px -{}px;background-size:{}px {}px">"#,
+        icon.width * scale, icon.height * scale, icon.x * scale, icon.y * scale,
+        atlas.image.width() as f64 * scale, atlas.image.height() as f64 * scale);
+    for &[start, end] in props.stretch_x.iter().flatten() {
+        let _ = writeln!(html, r#"<div class="stretch-x" style="left:{}px;width:{}px;top:0;bottom:0"></div>"#,
+                         start * scale, (end - start) * scale);
+    }
+    for &[start, end] in props.stretch_y.iter().flatten() {
+        let _ = writeln!(html, r#"<div class="stretch-y" style="top:{}px;height:{}px;left:0;right:0"></div>"#,
+                         start * scale, (end - start) * scale);
+    }
+    if let Some([left, top, right, bottom]) = props.content {
+        let _ = writeln!(html, r#"<div class="content" style="left:{}px;top:{}px;width:{}px;height:{}px"></div>"#,
+                         left * scale, top * scale, (right - left) * scale, (bottom - top) * scale);
+    }
+    html.push_str("</div>\n");
+    let _ = writeln!(html, r#"<div class="id">{}</div>"#, escape_html(id));
+    let _ = write!(html, "<div>{}x{} px {}", icon.width, icon.height, ratio_label(ratio));
+    if props.sdf {
+        html.push_str(", SDF");
+    }
+    html.push_str("</div>\n");
+    if let Some(path) = source_path {
+        let _ = writeln!(html, r#"<div class="path">{}</div>"#,
+                         escape_html(&pd(path).to_string()));
+    }
+    html.push_str("</div>\n");
+}
+
+/// Writes the preview page of the atlases (which all share the icon IDs).
+pub fn write(path: &Path, atlases: &[BuiltAtlas],
+             source_paths: &BTreeMap<String, PathBuf>) -> Result<()> {
+    let mut image_urls = vec![];
+    for atlas in atlases.iter() {
+        image_urls.push(format!("data:image/png;base64,{}",
+                                base64_encode(&encode::png(&atlas.image)?)));
+    }
+
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    let _ = writeln!(html, "<title>Sprite preview</title>\n<style>{}</style>", STYLE);
+    let _ = writeln!(html, "<script>{}</script>\n</head>\n<body>", SCRIPT);
+    html.push_str("<h1>Sprite preview</h1>\n");
+    html.push_str("<button onclick=\"toggleBackground()\">Toggle light/dark background</button>\n");
+    for (atlas, url) in atlases.iter().zip(image_urls.iter()) {
+        write_atlas(&mut html, atlas, url);
+    }
+
+    // The icon grid shows the highest resolution atlas
+    let grid = atlases.iter().zip(image_urls.iter())
+        .max_by(|(a, _), (b, _)| a.pixel_ratio.partial_cmp(&b.pixel_ratio).unwrap());
+    if let Some((atlas, url)) = grid {
+        let SpriteMetadata { icons } = &atlas.metadata;
+        let _ = writeln!(html, "<style>.grid-image {{ background-image: url({}); }}</style>", url);
+        let _ = writeln!(html, "<h2>Icons ({})</h2>\n<div class=\"grid\">", icons.len());
+        for (id, icon) in icons.iter() {
+            write_icon(&mut html, id, icon, atlas,
+                       source_paths.get(id).map(PathBuf::as_path));
+        }
+        html.push_str("</div>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+
+    println!("Saving {}", pd(path));
+    std::fs::write(path, html)?;
+    Ok(())
+}
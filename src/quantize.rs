@@ -0,0 +1,156 @@
+//! Lossy palette quantisation.
+//!
+//! Colors are reduced by median cut followed by a few k-means iterations,
+//! in premultiplied RGBA space, so that differences in (nearly) transparent
+//! areas count less. Fully transparent pixels keep a palette entry of their own.
+
+use std::collections::HashMap;
+
+type Color = [f64; 4];
+
+const KMEANS_ITERATIONS: usize = 3;
+
+/// A range of the color list, with its weighted sum of squared errors
+/// and the channel of the largest error.
+struct ColorBox {
+    start: usize,
+    end: usize,
+    error: f64,
+    channel: usize,
+}
+
+impl ColorBox {
+    fn new(colors: &[(Color, f64)], start: usize, end: usize) -> Self {
+        let mean = mean(&colors[start..end]);
+        let mut errors = [0.; 4];
+        for (color, weight) in colors[start..end].iter() {
+            for c in 0..4 {
+                errors[c] += weight * (color[c] - mean[c]).powi(2);
+            }
+        }
+        let channel = (0..4).fold(0, |best, c| if errors[c] > errors[best] { c } else { best });
+        Self { start, end, error: errors.iter().sum(), channel }
+    }
+}
+
+fn add_weighted(sum: &mut Color, color: &Color, weight: f64) {
+    for (s, c) in sum.iter_mut().zip(color) {
+        *s += c * weight;
+    }
+}
+
+fn mean(colors: &[(Color, f64)]) -> Color {
+    let mut sum = [0.; 4];
+    let mut total_weight = 0.;
+    for (color, weight) in colors {
+        add_weighted(&mut sum, color, *weight);
+        total_weight += weight;
+    }
+    sum.map(|s| s / total_weight)
+}
+
+fn distance(a: &Color, b: &Color) -> f64 {
+    (0..4).map(|c| (a[c] - b[c]).powi(2)).sum()
+}
+
+fn nearest(palette: &[Color], color: &Color) -> usize {
+    let mut best = (0, f64::INFINITY);
+    for (idx, entry) in palette.iter().enumerate() {
+        let d = distance(entry, color);
+        if d < best.1 {
+            best = (idx, d);
+        }
+    }
+    best.0
+}
+
+fn median_cut(colors: &mut [(Color, f64)], max_colors: usize) -> Vec<Color> {
+    let mut boxes = vec![ColorBox::new(colors, 0, colors.len())];
+    while boxes.len() < max_colors {
+        let (idx, _) = boxes.iter().enumerate()
+            .fold((0, 0.), |best, (idx, b)| if b.error > best.1 { (idx, b.error) } else { best });
+        let b = &boxes[idx];
+        if b.error == 0. {
+            break;
+        }
+        let (start, end, channel) = (b.start, b.end, b.channel);
+        let slice = &mut colors[start..end];
+        slice.sort_by(|a, b| a.0[channel].partial_cmp(&b.0[channel]).unwrap());
+        // Split at the weighted median, keeping both halves non-empty
+        let half_weight = slice.iter().map(|(_, w)| w).sum::<f64>() / 2.;
+        let mut weight = 0.;
+        let mut split = slice.len() - 1;
+        for (i, (_, w)) in slice.iter().enumerate() {
+            weight += w;
+            if weight >= half_weight {
+                split = (i + 1).clamp(1, slice.len() - 1);
+                break;
+            }
+        }
+        boxes[idx] = ColorBox::new(colors, start, start + split);
+        boxes.push(ColorBox::new(colors, start + split, end));
+    }
+    boxes.iter().map(|b| mean(&colors[b.start..b.end])).collect()
+}
+
+/// Reduces premultiplied RGBA pixels to at most `max_colors` colors (up to 256),
+/// returning the premultiplied palette, the palette index of each pixel and
+/// the mean squared error (summed over the channels, in the 0-1 range).
+pub fn quantize(pixels: &[[u8; 4]], max_colors: usize) -> (Vec<[u8; 4]>, Vec<u8>, f64) {
+    let mut histogram: HashMap<[u8; 4], f64> = HashMap::new();
+    for pixel in pixels.iter().filter(|p| p[3] != 0) {
+        *histogram.entry(*pixel).or_default() += 1.;
+    }
+    let mut colors: Vec<(Color, f64)> = histogram.into_iter()
+        .map(|(color, weight)| (color.map(|c| c as f64 / 255.), weight))
+        .collect();
+    // Hash map order is random
+    colors.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let mut palette = if colors.is_empty() {
+        vec![]
+    } else {
+        median_cut(&mut colors, max_colors.saturating_sub(1).max(1))
+    };
+    for _ in 0..KMEANS_ITERATIONS {
+        let mut sums = vec![([0.; 4], 0.); palette.len()];
+        for (color, weight) in colors.iter() {
+            let sum = &mut sums[nearest(&palette, color)];
+            add_weighted(&mut sum.0, color, *weight);
+            sum.1 += weight;
+        }
+        for (entry, (sum, weight)) in palette.iter_mut().zip(sums) {
+            if weight > 0. {
+                *entry = sum.map(|s| s / weight);
+            }
+        }
+    }
+
+    // Premultiplied color channels can not exceed alpha
+    let mut result: Vec<[u8; 4]> = palette.iter()
+        .map(|entry| {
+            let alpha = (entry[3] * 255.).round().clamp(1., 255.);
+            let channel = |c: f64| (c * 255.).round().clamp(0., alpha) as u8;
+            [channel(entry[0]), channel(entry[1]), channel(entry[2]), alpha as u8]
+        })
+        .collect();
+    let transparent = result.len();
+    result.push([0, 0, 0, 0]);
+    let palette: Vec<Color> = result.iter().map(|e| e.map(|c| c as f64 / 255.)).collect();
+
+    let mut cache: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut error = 0.;
+    let indices = pixels.iter()
+        .map(|pixel| {
+            if pixel[3] == 0 {
+                return transparent as u8;
+            }
+            let index = *cache.entry(*pixel).or_insert_with(|| {
+                nearest(&palette[..transparent], &pixel.map(|c| c as f64 / 255.)) as u8
+            });
+            error += distance(&palette[index as usize], &pixel.map(|c| c as f64 / 255.));
+            index
+        })
+        .collect();
+    (result, indices, error / pixels.len().max(1) as f64)
+}
@@ -0,0 +1,152 @@
+//! Pre-rendered raster (PNG) icon sources.
+//!
+//! A raster icon may be provided at several pixel ratios using the usual
+//! `name@2x.png` naming. Each image may have a `name@2x.json` sidecar with
+//! its pixel ratio, SDF flag, stretch zones and content box.
+//! Entries of existing sprite atlases are loaded as raster sources too.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use resvg::tiny_skia::{FilterQuality, Pixmap, PixmapPaint, Transform};
+
+use crate::sprite::{IconProperties, SpriteMetadata};
+use crate::{create_pixmap, pd, suffixed_base};
+
+pub struct RasterImage {
+    pub pixmap: Arc<Pixmap>,
+    pub properties: IconProperties,
+}
+
+pub struct RasterSource {
+    pub id: String,
+    pub input_path: PathBuf,
+    /// Images of the icon, at distinct pixel ratios
+    pub images: Vec<RasterImage>,
+}
+
+pub fn is_raster(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("png"))
+}
+
+/// Splits a `name@2x` file stem into the name and the pixel ratio.
+pub fn split_ratio_suffix(stem: &str) -> (&str, Option<f64>) {
+    stem.rsplit_once('@')
+        .and_then(|(name, suffix)| {
+            let ratio: f64 = suffix.strip_suffix('x')?.parse().ok()?;
+            (ratio > 0.).then(|| (name, Some(ratio)))
+        })
+        .unwrap_or((stem, None))
+}
+
+/// Resamples the image to the given size.
+pub fn resize(pixmap: &Pixmap, width: u32, height: u32) -> Result<Pixmap> {
+    let mut result = create_pixmap(width as f64, height as f64)?;
+    let paint = PixmapPaint { quality: FilterQuality::Bicubic, ..Default::default() };
+    let transform = Transform::from_scale(
+        width as f32 / pixmap.width() as f32,
+        height as f32 / pixmap.height() as f32);
+    result.draw_pixmap(0, 0, pixmap.as_ref(), &paint, transform, None)
+        .ok_or_else(|| anyhow!("Resizing image to {}x{} failed", width, height))?;
+    Ok(result)
+}
+
+impl RasterImage {
+    pub fn load(path: &Path) -> Result<Self> {
+        let pixmap = Pixmap::load_png(path)
+            .map_err(|e| anyhow!("{}: {}", pd(path), e))?;
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let properties = IconProperties::load_sidecar(&path.with_extension("json"))
+            .map_err(|e| anyhow!("{}: {}", pd(&path.with_extension("json")), e))?
+            .unwrap_or_else(|| IconProperties {
+                pixel_ratio: split_ratio_suffix(&stem).1.unwrap_or(1.),
+                ..Default::default()
+            });
+        Ok(Self { pixmap: Arc::new(pixmap), properties })
+    }
+}
+
+impl RasterSource {
+    /// Returns the image best suited for the given pixel ratio: an exact
+    /// match if there is one, otherwise the one with the highest resolution.
+    fn best_image(&self, pixel_ratio: f64) -> &RasterImage {
+        self.images.iter()
+            .find(|image| image.properties.pixel_ratio == pixel_ratio)
+            .or_else(|| self.images.iter().max_by(|a, b| {
+                a.properties.pixel_ratio.partial_cmp(&b.properties.pixel_ratio).unwrap()
+            }))
+            .expect("raster source without images")
+    }
+
+    /// Returns the icon image and properties at the given pixel ratio.
+    pub fn resolve(&self, pixel_ratio: f64) -> Result<(Arc<Pixmap>, IconProperties)> {
+        let image = self.best_image(pixel_ratio);
+        let scale = pixel_ratio / image.properties.pixel_ratio;
+        let properties = image.properties.transformed(scale, 0., pixel_ratio);
+        if scale == 1. {
+            return Ok((image.pixmap.clone(), properties));
+        }
+        // The following casts are saturating
+        let width = (image.pixmap.width() as f64 * scale).round().max(1.) as u32;
+        let height = (image.pixmap.height() as f64 * scale).round().max(1.) as u32;
+        Ok((Arc::new(resize(&image.pixmap, width, height)?), properties))
+    }
+}
+
+/// Loads raster icons, grouping `name.png`, `name@2x.png`, etc. into a single source.
+pub fn load_rasters(paths: Vec<PathBuf>) -> Result<Vec<RasterSource>> {
+    let mut groups: BTreeMap<(PathBuf, String), Vec<PathBuf>> = BTreeMap::new();
+    for path in paths {
+        let stem = path.file_stem()
+            .ok_or_else(|| anyhow!("Missing file name {}", pd(&path)))?
+            .to_string_lossy().into_owned();
+        let id = split_ratio_suffix(&stem).0.to_owned();
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        groups.entry((dir, id)).or_default().push(path);
+    }
+    groups.into_iter()
+        .map(|((_, id), paths)| {
+            let images = paths.iter()
+                .map(|path| RasterImage::load(path))
+                .collect::<Result<Vec<_>>>()?;
+            // The lowest resolution image represents the source in messages
+            let input_path = paths.iter().zip(images.iter())
+                .min_by(|(_, a), (_, b)| {
+                    a.properties.pixel_ratio.partial_cmp(&b.properties.pixel_ratio).unwrap()
+                })
+                .map(|(path, _)| path.clone())
+                .unwrap();
+            Ok(RasterSource { id, input_path, images })
+        })
+        .collect()
+}
+
+/// Loads the entries of an existing sprite (`base.json` and `base.png`, and
+/// `base@2x.json` and `base@2x.png` if present) as raster sources.
+pub fn load_sprite(base: &Path) -> Result<Vec<RasterSource>> {
+    let mut sources: BTreeMap<String, RasterSource> = BTreeMap::new();
+    for (idx, suffix) in ["", "@2x"].into_iter().enumerate() {
+        let sprite_base = suffixed_base(base, suffix);
+        let metadata_path = sprite_base.with_extension("json");
+        let image_path = sprite_base.with_extension("png");
+        if idx > 0 && !metadata_path.is_file() {
+            continue;
+        }
+        let (metadata, atlas) = SpriteMetadata::load_with_image(&metadata_path, &image_path)?;
+        for (id, icon) in metadata.icons.into_iter() {
+            let pixmap = icon.cut(&atlas)
+                .map_err(|e| anyhow!("{}: icon {}: {}", pd(&metadata_path), id, e))?;
+            let image = RasterImage { pixmap: Arc::new(pixmap), properties: icon.properties };
+            sources.entry(id.clone())
+                .or_insert_with(|| RasterSource {
+                    id,
+                    input_path: metadata_path.clone(),
+                    images: vec![],
+                })
+                .images.push(image);
+        }
+    }
+    Ok(sources.into_values().collect())
+}
@@ -0,0 +1,189 @@
+//! `serve` subcommand: a local HTTP server building the sprite on demand.
+//!
+//! Atlases are built lazily for each requested pixel ratio and cached until
+//! the modification times of the input files (including the fonts and the
+//! stylesheets of the SVG files) change. All responses allow
+//! cross-origin requests, so that style editors can load the sprite directly.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::{encode, load_sources, process, AtlasOptions, AtlasSources, InputOptions};
+
+/// Limits the atlas size of a request.
+const MAX_PIXEL_RATIO: f64 = 8.;
+
+pub struct ServeOptions<'a> {
+    pub port: u16,
+    /// Base name of the sprite in the URLs
+    pub name: &'a str,
+    pub buffer: Option<&'a svgtypes::Length>,
+    pub snap: Option<crate::snap::SnapMode>,
+    pub effects: crate::effects::Effects,
+    pub inputs: InputOptions<'a>,
+}
+
+type Fingerprint = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+/// A built atlas, as served.
+struct ServedAtlas {
+    metadata: String,
+    image: Vec<u8>,
+}
+
+struct Cache {
+    /// Input files and directories given in the options
+    watched: Vec<PathBuf>,
+    fingerprint: Fingerprint,
+    sources: Option<AtlasSources>,
+    atlases: BTreeMap<String, ServedAtlas>,
+}
+
+/// Returns the modification times and sizes of the input files,
+/// listing the directories to notice added and removed files too.
+fn fingerprint(paths: &[PathBuf]) -> Fingerprint {
+    let mut result = vec![];
+    for path in paths.iter() {
+        let entries: Vec<PathBuf> = match std::fs::read_dir(path) {
+            Ok(dir) => dir.filter_map(|entry| entry.ok().map(|e| e.path())).collect(),
+            Err(_) => vec![path.clone()],
+        };
+        for entry in entries {
+            let metadata = std::fs::metadata(&entry).ok();
+            let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+            result.push((entry, modified, metadata.map_or(0, |m| m.len())));
+        }
+    }
+    result.sort();
+    result
+}
+
+/// Parses a request path like `/sprite@2x.png` into the pixel ratio and the extension.
+fn parse_path<'a>(url: &'a str, name: &str) -> Option<(f64, &'a str)> {
+    let path = url.split('?').next()?.strip_prefix('/')?.strip_prefix(name)?;
+    let (suffix, extension) = path.rsplit_once('.')?;
+    let ratio = if suffix.is_empty() {
+        1.
+    } else {
+        suffix.strip_prefix('@')?.strip_suffix('x')?.parse().ok()?
+    };
+    (ratio > 0. && ratio <= MAX_PIXEL_RATIO).then(|| (ratio, extension))
+}
+
+impl Cache {
+    /// Returns the watched paths, with the stylesheets referenced by the loaded SVG files.
+    fn watched(&self) -> Vec<PathBuf> {
+        let mut result = self.watched.clone();
+        if let Some(ref sources) = self.sources {
+            result.extend(sources.stylesheets.iter().cloned());
+        }
+        result
+    }
+
+    /// Drops everything built from outdated input files.
+    fn refresh(&mut self) {
+        let fingerprint = fingerprint(&self.watched());
+        if fingerprint != self.fingerprint {
+            if self.sources.is_some() {
+                println!("Input files changed, rebuilding the atlases");
+            }
+            self.fingerprint = fingerprint;
+            self.sources = None;
+            self.atlases.clear();
+        }
+    }
+
+    fn atlas(&mut self, options: &ServeOptions, ratio: f64) -> Result<&ServedAtlas> {
+        if self.sources.is_none() {
+            self.sources = Some(load_sources(&options.inputs)?);
+            // The stylesheets are only known once the SVG files are loaded
+            self.fingerprint = fingerprint(&self.watched());
+        }
+        let sources = self.sources.as_ref().unwrap();
+        let key = ratio.to_string();
+        if !self.atlases.contains_key(&key) {
+            let atlas_options = AtlasOptions::new(options.buffer, ratio, options.snap,
+                                                 options.effects)?;
+            let atlas = process(sources, atlas_options, None, Path::new(options.name),
+                                options.inputs.verbose)?;
+            self.atlases.insert(key.clone(), ServedAtlas {
+                metadata: atlas.metadata.to_json().to_string(),
+                image: encode::png(&atlas.image)?,
+            });
+        }
+        Ok(&self.atlases[&key])
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+pub fn run(options: &ServeOptions) -> Result<()> {
+    let mut watched: Vec<PathBuf> = options.inputs.input_paths.to_vec();
+    watched.extend(options.inputs.css_override.map(Path::to_path_buf));
+    watched.extend(options.inputs.style.map(Path::to_path_buf));
+    watched.extend(options.inputs.compose.map(Path::to_path_buf));
+    watched.extend(options.inputs.font_dirs.iter().cloned());
+    watched.extend(options.inputs.fonts.iter().cloned());
+    for sprite_base in options.inputs.sprites.iter() {
+        let dir = sprite_base.parent().unwrap_or_else(|| Path::new(""));
+        watched.push(if dir.as_os_str().is_empty() { PathBuf::from(".") } else { dir.to_path_buf() });
+    }
+
+    let server = Server::http(("127.0.0.1", options.port)).map_err(|e| anyhow!(e))?;
+    println!("Serving http://127.0.0.1:{}/{}.json (and @Nx variants), press Ctrl+C to stop",
+             options.port, options.name);
+
+    let mut cache = Cache {
+        watched, fingerprint: vec![], sources: None, atlases: BTreeMap::new(),
+    };
+    for request in server.incoming_requests() {
+        let cors = header("Access-Control-Allow-Origin", "*");
+        if *request.method() == Method::Options {
+            let response = Response::from_data(vec![]).with_status_code(204)
+                .with_header(cors)
+                .with_header(header("Access-Control-Allow-Methods", "GET, HEAD, OPTIONS"))
+                .with_header(header("Access-Control-Allow-Headers", "*"));
+            let _ = request.respond(response);
+            continue;
+        }
+
+        let (ratio, content_type) = match parse_path(request.url(), options.name) {
+            Some((ratio, "json")) => (ratio, "application/json"),
+            Some((ratio, "png")) => (ratio, "image/png"),
+            _ => {
+                let response = Response::from_string("Not found").with_status_code(404)
+                    .with_header(cors);
+                let _ = request.respond(response);
+                continue;
+            },
+        };
+        let url = request.url().to_owned();
+        cache.refresh();
+        let response = match cache.atlas(options, ratio) {
+            Ok(atlas) => {
+                let data = if content_type == "image/png" {
+                    atlas.image.clone()
+                } else {
+                    atlas.metadata.clone().into_bytes()
+                };
+                Response::from_data(data).with_header(header("Content-Type", content_type))
+            },
+            Err(e) => {
+                println!("{}: {}", url, e);
+                Response::from_string(e.to_string()).with_status_code(500)
+            },
+        };
+        let response = response.with_header(cors)
+            .with_header(header("Cache-Control", "no-cache"));
+        if let Err(e) = request.respond(response) {
+            println!("{}: {}", url, e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Pixel-grid snapping, so that SVG icons stay crisp at each pixel ratio.
+//!
+//! The drawing keeps its scale, but is moved by the sub-pixel offset of its
+//! bounding box, and the icon size is rounded up to whole pixels. This is done
+//! by adjusting the viewBox of the SVG, which maps it to the pixels.
+
+use std::str::FromStr;
+
+use resvg::usvg::{self, NodeExt};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SnapMode {
+    /// Move the drawing to the nearest whole pixel
+    Align,
+    /// Centre the drawing in the icon on whole pixels
+    Center,
+}
+
+impl FromStr for SnapMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "align" => SnapMode::Align,
+            "center" | "centre" => SnapMode::Center,
+            _ => return Err(format!("Unknown snapping mode {:?}, expected align or center", s)),
+        })
+    }
+}
+
+/// Snapped size and viewBox of an SVG at a pixel ratio.
+#[derive(Debug, Copy, Clone)]
+pub struct Snap {
+    size: usvg::Size,
+    view_box: usvg::ViewBox,
+    /// Offset of the drawing in pixels
+    pub dx: f64,
+    pub dy: f64,
+}
+
+/// Returns the position of a drawing snapped to whole pixels in an icon of `size` pixels.
+fn snap_position(mode: SnapMode, position: f64, extent: f64, size: f64) -> f64 {
+    match mode {
+        SnapMode::Center if extent <= size => ((size - extent) / 2.).floor(),
+        _ => position.round(),
+    }
+}
+
+impl Snap {
+    /// Computes the snapping of a loaded SVG, `None` if it has nothing to draw.
+    pub fn new(tree: &usvg::Tree, mode: SnapMode) -> Option<Self> {
+        let view_box = tree.view_box;
+        let ts = usvg::utils::view_box_to_transform(view_box.rect, view_box.aspect, tree.size);
+        let bbox = tree.root.calculate_bbox()?.transform(&ts)?;
+        let (width, height) = (tree.size.width().ceil(), tree.size.height().ceil());
+        let x = snap_position(mode, bbox.x(), bbox.width(), width);
+        let y = snap_position(mode, bbox.y(), bbox.height(), height);
+        let (dx, dy) = (x - bbox.x(), y - bbox.y());
+        // The moved drawing may need another pixel
+        let width = width.max((bbox.right() + dx).ceil());
+        let height = height.max((bbox.bottom() + dy).ceil());
+
+        // Without the aspect ratio, the viewBox is mapped exactly to the pixels
+        let rect = usvg::Rect::new(-(ts.e + dx) / ts.a, -(ts.f + dy) / ts.d,
+                                   width / ts.a, height / ts.d)?;
+        Some(Self {
+            size: usvg::Size::new(width, height)?,
+            view_box: usvg::ViewBox {
+                rect,
+                aspect: usvg::AspectRatio { align: usvg::Align::None, ..Default::default() },
+            },
+            dx,
+            dy,
+        })
+    }
+
+    pub fn apply(&self, tree: &mut usvg::Tree) {
+        tree.size = self.size;
+        tree.view_box = self.view_box;
+    }
+
+    /// Describes the sub-pixel offset corrected by the snapping, if any.
+    pub fn correction(&self) -> Option<String> {
+        let offset = |d: f64| {
+            let fract = d - d.round();
+            (fract.abs() > 1e-3).then(|| fract)
+        };
+        match (offset(self.dx), offset(self.dy)) {
+            (None, None) => None,
+            (dx, dy) => Some(format!("moved by a sub-pixel offset of {:.3}x{:.3} px",
+                                     dx.unwrap_or(0.), dy.unwrap_or(0.))),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Mapbox sprite metadata (`sprite.json`) model.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use resvg::tiny_skia::{IntRect, Pixmap};
+use serde_json::{json, Map, Value};
+
+use crate::pd;
+
+/// Icon metadata that does not depend on the atlas layout.
+///
+/// Stretch zones and the content box are in pixels at `pixel_ratio`,
+/// relative to the top-left corner of the icon.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IconProperties {
+    pub pixel_ratio: f64,
+    pub sdf: bool,
+    pub stretch_x: Option<Vec<[f64; 2]>>,
+    pub stretch_y: Option<Vec<[f64; 2]>>,
+    pub content: Option<[f64; 4]>,
+    /// Other fields of the icon in a merged sprite or a sidecar, written back unchanged
+    pub extra: Map<String, Value>,
+}
+
+/// Fields of the icon metadata with a meaning known to us.
+const KNOWN_FIELDS: &[&str] = &["width", "height", "x", "y",
+                                "pixelRatio", "sdf", "stretchX", "stretchY", "content"];
+
+/// Metadata of a single sprite image, as described in the style specification.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IconMetadata {
+    pub width: f64,
+    pub height: f64,
+    pub x: f64,
+    pub y: f64,
+    pub properties: IconProperties,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpriteMetadata {
+    pub icons: BTreeMap<String, IconMetadata>,
+}
+
+fn number(value: &Value, name: &str) -> Result<f64> {
+    value.get(name)
+        .ok_or_else(|| anyhow!("missing \"{}\"", name))?
+        .as_f64()
+        .ok_or_else(|| anyhow!("\"{}\" is not a number", name))
+}
+
+fn numbers<const N: usize>(value: &Value) -> Result<[f64; N]> {
+    let mut result = [0.; N];
+    match value.as_array() {
+        Some(items) if items.len() == N => {
+            for (r, item) in result.iter_mut().zip(items) {
+                *r = item.as_f64().ok_or_else(|| anyhow!("{} is not a number", item))?;
+            }
+        },
+        _ => bail!("{} is not an array of {} numbers", value, N),
+    }
+    Ok(result)
+}
+
+fn stretch_zones(value: &Value, name: &str) -> Result<Option<Vec<[f64; 2]>>> {
+    value.get(name).map(|zones| {
+        zones.as_array()
+            .ok_or_else(|| anyhow!("\"{}\" is not an array", name))?
+            .iter().map(numbers::<2>).collect()
+    }).transpose()
+}
+
+impl Default for IconProperties {
+    fn default() -> Self {
+        Self {
+            pixel_ratio: 1.,
+            sdf: false,
+            stretch_x: None,
+            stretch_y: None,
+            content: None,
+            extra: Map::new(),
+        }
+    }
+}
+
+impl IconProperties {
+    pub fn from_json(value: &Value) -> Result<Self> {
+        Ok(Self {
+            pixel_ratio: value.get("pixelRatio").map_or(Ok(1.), |_| number(value, "pixelRatio"))?,
+            sdf: value.get("sdf").and_then(Value::as_bool).unwrap_or(false),
+            stretch_x: stretch_zones(value, "stretchX")?,
+            stretch_y: stretch_zones(value, "stretchY")?,
+            content: value.get("content").map(numbers::<4>).transpose()?,
+            extra: value.as_object().into_iter().flatten()
+                .filter(|(name, _)| !KNOWN_FIELDS.contains(&name.as_str()))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+        })
+    }
+
+    /// Loads a sidecar metadata file, if it exists.
+    pub fn load_sidecar(path: &Path) -> Result<Option<Self>> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let value: Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        Ok(Some(Self::from_json(&value)?))
+    }
+
+    /// Returns the properties of the icon image scaled by `scale` to `pixel_ratio`
+    /// and shifted by `offset` pixels (e.g. for the buffer around the icon).
+    pub fn transformed(&self, scale: f64, offset: f64, pixel_ratio: f64) -> Self {
+        self.mapped((scale, scale), (offset, offset), pixel_ratio)
+    }
+
+    /// Returns the properties shifted by `dx` and `dy` pixels (e.g. for a moved drawing).
+    pub fn shifted(&self, dx: f64, dy: f64) -> Self {
+        self.mapped((1., 1.), (dx, dy), self.pixel_ratio)
+    }
+
+    /// Returns the properties scaled and then shifted on each axis to `pixel_ratio`
+    /// (e.g. by the viewBox transform of an SVG icon).
+    pub fn mapped(&self, (sx, sy): (f64, f64), (dx, dy): (f64, f64), pixel_ratio: f64) -> Self {
+        let map_zones = |zones: &Vec<[f64; 2]>, s: f64, d: f64| -> Vec<[f64; 2]> {
+            zones.iter().map(|&[a, b]| [a * s + d, b * s + d]).collect()
+        };
+        Self {
+            pixel_ratio,
+            sdf: self.sdf,
+            stretch_x: self.stretch_x.as_ref().map(|zones| map_zones(zones, sx, dx)),
+            stretch_y: self.stretch_y.as_ref().map(|zones| map_zones(zones, sy, dy)),
+            content: self.content.map(|[x1, y1, x2, y2]| {
+                [x1 * sx + dx, y1 * sy + dy, x2 * sx + dx, y2 * sy + dy]
+            }),
+            extra: self.extra.clone(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut result = Value::Object(self.extra.clone());
+        result["pixelRatio"] = json!(self.pixel_ratio);
+        if self.sdf {
+            result["sdf"] = json!(true);
+        }
+        if let Some(ref zones) = self.stretch_x {
+            result["stretchX"] = json!(zones);
+        }
+        if let Some(ref zones) = self.stretch_y {
+            result["stretchY"] = json!(zones);
+        }
+        if let Some(ref content) = self.content {
+            result["content"] = json!(content);
+        }
+        result
+    }
+}
+
+impl IconMetadata {
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let properties = IconProperties::from_json(value)?;
+        Ok(Self {
+            width: number(value, "width")?,
+            height: number(value, "height")?,
+            x: number(value, "x")?,
+            y: number(value, "y")?,
+            properties,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut result = self.properties.to_json();
+        result["width"] = json!(self.width);
+        result["height"] = json!(self.height);
+        result["x"] = json!(self.x);
+        result["y"] = json!(self.y);
+        result
+    }
+
+    /// Copies the icon image out of the atlas image.
+    pub fn cut(&self, atlas: &Pixmap) -> Result<Pixmap> {
+        let outside = || anyhow!("Icon rectangle {}x{}+{}+{} is outside of the atlas",
+                                 self.width, self.height, self.x, self.y);
+        if self.x < 0. || self.y < 0.
+            || self.x + self.width > atlas.width() as f64
+            || self.y + self.height > atlas.height() as f64 {
+            return Err(outside());
+        }
+        // The following casts are saturating
+        IntRect::from_xywh(self.x as i32, self.y as i32, self.width as u32, self.height as u32)
+            .and_then(|rect| atlas.clone_rect(rect))
+            .ok_or_else(outside)
+    }
+}
+
+impl SpriteMetadata {
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let entries = value.as_object()
+            .ok_or_else(|| anyhow!("Sprite metadata is not a JSON object"))?;
+        let mut icons = BTreeMap::new();
+        for (id, entry) in entries.iter() {
+            let icon = IconMetadata::from_json(entry)
+                .map_err(|e| anyhow!("Icon {}: {}", id, e))?;
+            icons.insert(id.clone(), icon);
+        }
+        Ok(Self { icons })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let value: Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        Self::from_json(&value)
+    }
+
+    /// Loads sprite metadata together with its atlas image.
+    pub fn load_with_image(metadata_path: &Path, image_path: &Path) -> Result<(Self, Pixmap)> {
+        let metadata = Self::load(metadata_path)
+            .map_err(|e| anyhow!("{}: {}", pd(metadata_path), e))?;
+        let atlas = Pixmap::load_png(image_path)
+            .map_err(|e| anyhow!("{}: {}", pd(image_path), e))?;
+        Ok((metadata, atlas))
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut result = json!({});
+        for (id, icon) in self.icons.iter() {
+            result[id.clone()] = icon.to_json();
+        }
+        result
+    }
+}
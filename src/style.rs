@@ -0,0 +1,218 @@
+//! Static analysis of MapLibre/Mapbox GL style documents.
+//!
+//! Only the properties that reference sprite images are considered.
+//! Expressions are not evaluated: every branch that could produce an image
+//! name is collected, and parts that depend on feature data are kept as
+//! wildcards to be matched against the available icon IDs.
+
+use std::fmt::Formatter;
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+/// Layout and paint properties that take a sprite image name.
+pub const IMAGE_PROPERTIES: [(&str, &str); 5] = [
+    ("layout", "icon-image"),
+    ("paint", "fill-pattern"),
+    ("paint", "line-pattern"),
+    ("paint", "background-pattern"),
+    ("paint", "fill-extrusion-pattern"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Literal(String),
+    /// Any (possibly empty) string, e.g. a `{token}` or a `["get", ...]`
+    Any,
+}
+
+/// A single image name (or a family of names) a style layer may request.
+#[derive(Debug, Clone)]
+pub struct IconReference {
+    pub layer_id: String,
+    pub property: &'static str,
+    pub pattern: Vec<Segment>,
+    /// The icon is stretched to fit the label text (`icon-text-fit`)
+    pub text_fit: bool,
+}
+
+impl IconReference {
+    /// Returns `true` if the reference matches every possible ID.
+    pub fn is_unconstrained(&self) -> bool {
+        self.pattern.iter().all(|s| *s == Segment::Any)
+    }
+
+    /// Returns `true` if the image is used as a repeated pattern fill.
+    pub fn is_pattern(&self) -> bool {
+        self.property.ends_with("-pattern")
+    }
+
+    pub fn matches(&self, id: &str) -> bool {
+        match_segments(&self.pattern, id)
+    }
+}
+
+/// Returns `true` if any of the references may request the given ID.
+pub fn is_referenced(references: &[IconReference], id: &str) -> bool {
+    references.iter().any(|r| r.matches(id))
+}
+
+impl std::fmt::Display for IconReference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "layer '{}' {} '", self.layer_id, self.property)?;
+        for segment in self.pattern.iter() {
+            match segment {
+                Segment::Literal(s) => f.write_str(s)?,
+                Segment::Any => f.write_str("*")?,
+            }
+        }
+        f.write_str("'")
+    }
+}
+
+fn match_segments(pattern: &[Segment], s: &str) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((Segment::Literal(lit), rest)) => s.strip_prefix(lit.as_str())
+            .map_or(false, |tail| match_segments(rest, tail)),
+        Some((Segment::Any, rest)) => s.char_indices().map(|(idx, _)| idx)
+            .chain(std::iter::once(s.len()))
+            .any(|idx| match_segments(rest, &s[idx..])),
+    }
+}
+
+/// Appends a segment, merging adjacent literals and wildcards.
+fn push_segment(pattern: &mut Vec<Segment>, segment: Segment) {
+    match (pattern.last_mut(), segment) {
+        (_, Segment::Literal(s)) if s.is_empty() => {},
+        (Some(Segment::Literal(last)), Segment::Literal(s)) => last.push_str(&s),
+        (Some(Segment::Any), Segment::Any) => {},
+        (_, segment) => pattern.push(segment),
+    }
+}
+
+/// Parses a legacy string value with `{token}` placeholders.
+fn parse_template(s: &str) -> Vec<Segment> {
+    let mut pattern = vec![];
+    let mut rest = s;
+    while let Some(start) = rest.find('{') {
+        match rest[start..].find('}') {
+            Some(len) => {
+                push_segment(&mut pattern, Segment::Literal(rest[..start].to_owned()));
+                push_segment(&mut pattern, Segment::Any);
+                rest = &rest[start + len + 1..];
+            },
+            None => break,
+        }
+    }
+    push_segment(&mut pattern, Segment::Literal(rest.to_owned()));
+    pattern
+}
+
+/// Collects all patterns an expression may evaluate to.
+fn expression_patterns(expr: &Value) -> Vec<Vec<Segment>> {
+    let any = || vec![vec![Segment::Any]];
+    let args = match expr {
+        Value::String(s) => {
+            let mut pattern = vec![];
+            push_segment(&mut pattern, Segment::Literal(s.clone()));
+            return vec![pattern];
+        },
+        Value::Array(args) => args,
+        _ => return any(),
+    };
+    let (op, args) = match args.split_first() {
+        Some((Value::String(op), args)) => (op.as_str(), args),
+        _ => return any(),
+    };
+    // Arguments that may be returned as the expression result
+    let outputs: Vec<&Value> = match op {
+        "literal" | "image" | "to-string" => args.iter().take(1).collect(),
+        "coalesce" => args.iter().collect(),
+        // ["case", cond, out, cond, out, ..., fallback]
+        "case" => args.iter().enumerate()
+            .filter(|(idx, _)| idx % 2 == 1 || *idx == args.len() - 1)
+            .map(|(_, arg)| arg).collect(),
+        // ["match", input, label, out, label, out, ..., fallback]
+        "match" => args.iter().enumerate().skip(1)
+            .filter(|(idx, _)| idx % 2 == 0 || *idx == args.len() - 1)
+            .map(|(_, arg)| arg).collect(),
+        // ["step", input, out, stop, out, stop, out, ...]
+        "step" => args.iter().enumerate().skip(1)
+            .filter(|(idx, _)| idx % 2 == 1)
+            .map(|(_, arg)| arg).collect(),
+        "let" => args.last().into_iter().collect(),
+        "concat" => {
+            let mut result: Vec<Vec<Segment>> = vec![vec![]];
+            for arg in args {
+                let parts = expression_patterns(arg);
+                result = result.iter()
+                    .flat_map(|prefix| parts.iter().map(move |part| {
+                        let mut pattern = prefix.clone();
+                        for segment in part.iter().cloned() {
+                            push_segment(&mut pattern, segment);
+                        }
+                        pattern
+                    }))
+                    .collect();
+            }
+            return result;
+        },
+        _ => return any(),
+    };
+    outputs.into_iter().flat_map(expression_patterns).collect()
+}
+
+/// Collects all patterns a property value (legacy or expression) may produce.
+fn value_patterns(value: &Value) -> Vec<Vec<Segment>> {
+    match value {
+        Value::String(s) => vec![parse_template(s)],
+        Value::Array(_) => expression_patterns(value),
+        Value::Object(function) => {
+            if function.get("type").and_then(Value::as_str) == Some("identity") {
+                return vec![vec![Segment::Any]];
+            }
+            let stops = function.get("stops").and_then(Value::as_array);
+            stops.into_iter().flatten()
+                .filter_map(|stop| stop.get(1))
+                .chain(function.get("default"))
+                .flat_map(value_patterns)
+                .collect()
+        },
+        _ => vec![],
+    }
+}
+
+/// Collects every sprite image reference of the style layers.
+pub fn collect_references(style: &Value) -> Result<Vec<IconReference>> {
+    let layers = match style.get("layers").and_then(Value::as_array) {
+        Some(layers) => layers,
+        None => bail!("Style has no \"layers\" array"),
+    };
+    let mut result = vec![];
+    for layer in layers.iter() {
+        let layer_id = layer.get("id").and_then(Value::as_str).unwrap_or("<unnamed>");
+        let text_fit = layer.get("layout")
+            .and_then(|layout| layout.get("icon-text-fit"))
+            .map_or(false, |fit| fit.as_str() != Some("none"));
+        for (group, property) in IMAGE_PROPERTIES {
+            let value = match layer.get(group).and_then(|g| g.get(property)) {
+                Some(value) => value,
+                None => continue,
+            };
+            for pattern in value_patterns(value) {
+                if pattern.is_empty() {
+                    // An empty image name hides the icon
+                    continue;
+                }
+                result.push(IconReference {
+                    layer_id: layer_id.to_owned(),
+                    property,
+                    pattern,
+                    text_fit: text_fit && property == "icon-image",
+                });
+            }
+        }
+    }
+    Ok(result)
+}
@@ -0,0 +1,269 @@
+//! SVG sprite with a `<symbol>` per icon, to be used as `<use href="sprite.svg#id"/>`.
+//!
+//! Stylesheets are inlined into `style` attributes, as they would otherwise
+//! apply to the whole document, and the internal IDs (gradients, clip paths,
+//! etc.) are prefixed with the icon ID, so that the icons do not collide.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use resvg::usvg;
+use xmltree::{Element, EmitterConfig, Namespace, XMLNode};
+
+use crate::{pd, SvgSource};
+
+pub const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+
+/// Root attributes which do not apply to a `<symbol>` (or a nested `<svg>`).
+pub const DROPPED_ROOT_ATTRIBUTES: &[&str] = &["width", "height", "x", "y", "viewBox",
+                                                "version", "baseProfile", "id"];
+
+/// An element of a tree, identified by the child indices leading to it from the root.
+#[derive(Clone)]
+struct TreeElement<'a> {
+    root: &'a Element,
+    path: Vec<usize>,
+}
+
+impl<'a> TreeElement<'a> {
+    fn element(&self) -> &'a Element {
+        let mut element = self.root;
+        for &idx in self.path.iter() {
+            element = match &element.children[idx] {
+                XMLNode::Element(child) => child,
+                _ => unreachable!(),
+            };
+        }
+        element
+    }
+
+    fn child_elements(&self) -> Vec<TreeElement<'a>> {
+        self.element().children.iter().enumerate()
+            .filter(|(_, node)| matches!(node, XMLNode::Element(_)))
+            .map(|(idx, _)| {
+                let mut path = self.path.clone();
+                path.push(idx);
+                TreeElement { root: self.root, path }
+            })
+            .collect()
+    }
+}
+
+impl simplecss::Element for TreeElement<'_> {
+    fn parent_element(&self) -> Option<Self> {
+        let mut path = self.path.clone();
+        path.pop()?;
+        Some(TreeElement { root: self.root, path })
+    }
+
+    fn prev_sibling_element(&self) -> Option<Self> {
+        let (&last, parent_path) = self.path.split_last()?;
+        let parent = TreeElement { root: self.root, path: parent_path.to_vec() };
+        let siblings = &parent.element().children;
+        let idx = (0..last).rev().find(|&idx| matches!(siblings[idx], XMLNode::Element(_)))?;
+        let mut path = parent_path.to_vec();
+        path.push(idx);
+        Some(TreeElement { root: self.root, path })
+    }
+
+    fn has_local_name(&self, name: &str) -> bool {
+        self.element().name == name
+    }
+
+    fn attribute_matches(&self, local_name: &str, operator: simplecss::AttributeOperator) -> bool {
+        self.element().attributes.get(local_name).map_or(false, |value| operator.matches(value))
+    }
+
+    fn pseudo_class_matches(&self, class: simplecss::PseudoClass) -> bool {
+        match class {
+            simplecss::PseudoClass::FirstChild => self.prev_sibling_element().is_none(),
+            _ => false,
+        }
+    }
+}
+
+fn element_at<'a>(root: &'a mut Element, path: &[usize]) -> &'a mut Element {
+    let mut element = root;
+    for &idx in path {
+        element = match &mut element.children[idx] {
+            XMLNode::Element(child) => child,
+            _ => unreachable!(),
+        };
+    }
+    element
+}
+
+/// Removes the `<style>` elements, returning their contents.
+fn take_style_sheets(element: &mut Element) -> String {
+    let mut result = String::new();
+    element.children.retain(|node| match node {
+        XMLNode::Element(child) if child.name == "style" => {
+            for text in child.children.iter() {
+                if let XMLNode::Text(text) | XMLNode::CData(text) = text {
+                    result.push_str(text);
+                    result.push('\n');
+                }
+            }
+            false
+        },
+        _ => true,
+    });
+    for node in element.children.iter_mut() {
+        if let XMLNode::Element(child) = node {
+            result.push_str(&take_style_sheets(child));
+        }
+    }
+    result
+}
+
+/// Moves the stylesheet declarations into the `style` attributes of the matching elements.
+fn inline_style_sheets(root: &mut Element) {
+    let css = take_style_sheets(root);
+    let mut style_sheet = simplecss::StyleSheet::parse(&css);
+    // Later rules of the same specificity take precedence
+    style_sheet.rules.sort_by_key(|rule| rule.selector.specificity());
+
+    let mut styles: Vec<(Vec<usize>, String)> = vec![];
+    let mut pending = vec![TreeElement { root, path: vec![] }];
+    while let Some(element) = pending.pop() {
+        let mut style = String::new();
+        for rule in style_sheet.rules.iter() {
+            if rule.selector.matches(&element) {
+                for declaration in rule.declarations.iter() {
+                    style.push_str(&format!("{}:{};", declaration.name, declaration.value));
+                }
+            }
+        }
+        if !style.is_empty() {
+            styles.push((element.path.clone(), style));
+        }
+        pending.extend(element.child_elements());
+    }
+
+    for (path, style) in styles {
+        let element = element_at(root, &path);
+        // The existing style attribute takes precedence over the stylesheet
+        let style = match element.attributes.get("style") {
+            Some(existing) => format!("{}{}", style, existing),
+            None => style,
+        };
+        element.attributes.insert("style".to_owned(), style);
+    }
+}
+
+/// Drops the elements of other namespaces (like editor metadata) and the
+/// namespace declarations, which are all made on the sprite root.
+fn strip_namespaces(element: &mut Element) {
+    element.namespaces = None;
+    element.children.retain(|node| match node {
+        XMLNode::Element(child) => child.namespace.as_deref() == Some(SVG_NAMESPACE),
+        _ => true,
+    });
+    for node in element.children.iter_mut() {
+        if let XMLNode::Element(child) = node {
+            strip_namespaces(child);
+        }
+    }
+}
+
+fn collect_ids(element: &Element, ids: &mut BTreeMap<String, String>, prefix: &str) {
+    if let Some(id) = element.attributes.get("id") {
+        ids.insert(id.clone(), format!("{}__{}", prefix, id));
+    }
+    for node in element.children.iter() {
+        if let XMLNode::Element(child) = node {
+            collect_ids(child, ids, prefix);
+        }
+    }
+}
+
+/// Renames the IDs and the references to them (`#id` links and `url(#id)`).
+fn rename_ids(element: &mut Element, ids: &BTreeMap<String, String>) {
+    for (name, value) in element.attributes.iter_mut() {
+        if name == "id" {
+            *value = ids[value.as_str()].clone();
+            continue;
+        }
+        // Both `href` and `xlink:href`, as the attribute names are without prefixes;
+        // other values like `fill="#fff"` are colors, even if an ID is the same
+        if name == "href" {
+            if let Some(new_id) = value.strip_prefix('#').and_then(|id| ids.get(id)) {
+                *value = format!("#{}", new_id);
+            }
+            continue;
+        }
+        if value.contains("url(") {
+            for (id, new_id) in ids.iter() {
+                for quote in ["", "'", "\""] {
+                    *value = value.replace(&format!("url({}#{}{})", quote, id, quote),
+                                           &format!("url({}#{}{})", quote, new_id, quote));
+                }
+            }
+        }
+    }
+    for node in element.children.iter_mut() {
+        if let XMLNode::Element(child) = node {
+            rename_ids(child, ids);
+        }
+    }
+}
+
+/// Parses the root element of an SVG icon for embedding into another document,
+/// returning it with the viewBox.
+pub fn embeddable_root(source: &SvgSource) -> Result<(Element, usvg::Rect)> {
+    let options = usvg::Options {
+        image_href_resolver: crate::images::href_resolver(&source.input_path, None),
+        ..Default::default()
+    };
+    let tree = usvg::Tree::from_data(&source.svg_data, &options)
+        .map_err(|e| anyhow!("{}: {}", pd(&source.input_path), e))?;
+    let data = source.svg_data.as_slice();
+    let data = data.strip_prefix(&[0xEF, 0xBB, 0xBF]).unwrap_or(data);
+    let mut root = Element::parse(data)
+        .map_err(|e| anyhow!("{}: {}", pd(&source.input_path), e))?;
+
+    inline_style_sheets(&mut root);
+    strip_namespaces(&mut root);
+    let mut ids = BTreeMap::new();
+    collect_ids(&root, &mut ids, &source.id);
+    rename_ids(&mut root, &ids);
+    Ok((root, tree.view_box.rect))
+}
+
+/// Creates an `<svg>` root element declaring the SVG namespace.
+pub fn new_root() -> Element {
+    let mut root = Element::new("svg");
+    root.namespace = Some(SVG_NAMESPACE.to_owned());
+    let mut namespaces = Namespace::empty();
+    namespaces.force_put("", SVG_NAMESPACE);
+    root.namespaces = Some(namespaces);
+    root
+}
+
+fn symbol(source: &SvgSource) -> Result<Element> {
+    let (root, rect) = embeddable_root(source)?;
+    let mut result = Element::new("symbol");
+    result.namespace = Some(SVG_NAMESPACE.to_owned());
+    result.attributes.insert("id".to_owned(), source.id.clone());
+    result.attributes.insert("viewBox".to_owned(), format!(
+        "{} {} {} {}", rect.x(), rect.y(), rect.width(), rect.height()));
+    for (name, value) in root.attributes.iter() {
+        if !DROPPED_ROOT_ATTRIBUTES.contains(&name.as_str()) {
+            result.attributes.insert(name.clone(), value.clone());
+        }
+    }
+    result.children = root.children;
+    Ok(result)
+}
+
+/// Generates the sprite from the SVG icons.
+pub fn generate(sources: &[SvgSource]) -> Result<String> {
+    let mut root = new_root();
+    for source in sources.iter() {
+        root.children.push(XMLNode::Element(symbol(source)?));
+    }
+    let mut result = vec![];
+    root.write_with_config(&mut result, EmitterConfig::new().perform_indent(true))?;
+    result.push(b'\n');
+    Ok(String::from_utf8(result)?)
+}
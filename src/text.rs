@@ -0,0 +1,150 @@
+//! Text in SVG icons, converted to paths with the `text` feature.
+//!
+//! Only the fonts given on the command line are used, never the system ones,
+//! so that the atlas is the same on every machine. The family of the first
+//! loaded font is the fallback for missing and generic font families.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+#[cfg(not(feature = "text"))]
+use anyhow::bail;
+use resvg::usvg;
+#[cfg(feature = "text")]
+use usvg_text_layout::{fontdb, TreeTextToPath};
+
+#[cfg_attr(not(feature = "text"), derive(Default))]
+pub struct Fonts {
+    #[cfg(feature = "text")]
+    db: fontdb::Database,
+    /// Family of the fallback font
+    pub default_family: Option<String>,
+}
+
+// fontdb::Database does not implement Default
+#[cfg(feature = "text")]
+impl Default for Fonts {
+    fn default() -> Self {
+        Self { db: fontdb::Database::new(), default_family: None }
+    }
+}
+
+fn text_nodes(tree: &usvg::Tree) -> usize {
+    tree.root.descendants()
+        .filter(|node| matches!(*node.borrow(), usvg::NodeKind::Text(_)))
+        .count()
+}
+
+/// Returns the font files in the given files and directories, in a stable order.
+#[cfg(feature = "text")]
+fn font_files(dirs: &[PathBuf], files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut result = vec![];
+    for dir in dirs.iter() {
+        let walker = globwalk::GlobWalkerBuilder::new(dir, "*.{ttf,otf,ttc,otc}")
+            .case_insensitive(true)
+            .build()?;
+        let mut dir_files: Vec<PathBuf> = walker
+            .filter_map(|r| r.map_err(|err| println!("{}", err)).ok().map(|e| e.into_path()))
+            .collect();
+        // Directory order is file system dependent
+        dir_files.sort();
+        result.extend(dir_files);
+    }
+    result.extend(files.iter().cloned());
+    Ok(result)
+}
+
+impl Fonts {
+    /// Loads the fonts from the directories (recursively) and the font files.
+    #[cfg(feature = "text")]
+    pub fn load(dirs: &[PathBuf], files: &[PathBuf]) -> Result<Self> {
+        let mut db = fontdb::Database::new();
+        for path in font_files(dirs, files)? {
+            db.load_font_file(&path).map_err(|e| anyhow::anyhow!("{}: {}", crate::pd(&path), e))?;
+        }
+        let default_family = db.faces().first().map(|face| face.family.clone());
+        if let Some(ref family) = default_family {
+            db.set_serif_family(family.as_str());
+            db.set_sans_serif_family(family.as_str());
+            db.set_cursive_family(family.as_str());
+            db.set_fantasy_family(family.as_str());
+            db.set_monospace_family(family.as_str());
+            println!("Loaded {} font faces, falling back to {}", db.len(), family);
+        }
+        Ok(Self { db, default_family })
+    }
+
+    #[cfg(not(feature = "text"))]
+    pub fn load(dirs: &[PathBuf], files: &[PathBuf]) -> Result<Self> {
+        if !dirs.is_empty() || !files.is_empty() {
+            bail!("Fonts can only be used with the text feature");
+        }
+        Ok(Self::default())
+    }
+
+    #[cfg(feature = "text")]
+    fn has_family(&self, family: &str) -> bool {
+        let family = match family {
+            "serif" => fontdb::Family::Serif,
+            "sans-serif" => fontdb::Family::SansSerif,
+            "cursive" => fontdb::Family::Cursive,
+            "fantasy" => fontdb::Family::Fantasy,
+            "monospace" => fontdb::Family::Monospace,
+            name => fontdb::Family::Name(name),
+        };
+        let families = [family];
+        self.db.query(&fontdb::Query { families: &families, ..Default::default() }).is_some()
+    }
+
+    /// Converts the text of an SVG to paths, returning the problems.
+    ///
+    /// Missing font families fall back to the default one, and text which
+    /// still produces no glyphs is reported as not shaped.
+    #[cfg(feature = "text")]
+    pub fn convert_text(&self, tree: &mut usvg::Tree) -> Vec<String> {
+        if text_nodes(tree) == 0 {
+            return vec![];
+        }
+        let mut result = vec![];
+        for node in tree.root.descendants() {
+            if let usvg::NodeKind::Text(ref mut text) = *node.borrow_mut() {
+                for span in text.chunks.iter_mut().flat_map(|chunk| chunk.spans.iter_mut()) {
+                    let families = &mut span.font.families;
+                    if families.iter().any(|family| self.has_family(family)) {
+                        continue;
+                    }
+                    let message = format!("needs a missing font family {}", families.join(", "));
+                    if !result.contains(&message) {
+                        result.push(message);
+                    }
+                    families.extend(self.default_family.clone());
+                }
+            }
+        }
+
+        let count_paths = |tree: &usvg::Tree| tree.root.descendants()
+            .filter(|node| matches!(*node.borrow(), usvg::NodeKind::Path(_)))
+            .count();
+        let paths = count_paths(tree);
+        tree.convert_text(&self.db, false);
+        if count_paths(tree) == paths {
+            result.push("contains text that could not be shaped".to_owned());
+        }
+        result
+    }
+
+    #[cfg(not(feature = "text"))]
+    pub fn convert_text(&self, tree: &mut usvg::Tree) -> Vec<String> {
+        if text_nodes(tree) == 0 {
+            return vec![];
+        }
+        vec!["contains text, which is only rendered with the text feature".to_owned()]
+    }
+
+    /// Sets the fallback font family for text without one.
+    pub fn apply(&self, options: &mut usvg::Options) {
+        if let Some(ref family) = self.default_family {
+            options.font_family = family.clone();
+        }
+    }
+}
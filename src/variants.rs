@@ -0,0 +1,188 @@
+//! State variants of icons (like inactive or highlighted ones), post-processed
+//! from the rendered icon images with colour filters.
+//!
+//! A variant is given as `[DIR=]SUFFIX:FILTERS`, e.g. `--variant='-inactive:greyscale
+//! opacity(50%)'` or `--variant 'poi=-hover:tint(#0066ff)'` (a value starting with
+//! `-` must follow `=`, otherwise it is taken for an option). The filters are applied
+//! in order, as with the CSS `filter` property, and the variant of an icon is added
+//! to the atlas with the suffixed ID and the same metadata.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use resvg::tiny_skia::{ColorU8, Pixmap};
+
+/// A colour filter, working on non-premultiplied colours.
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    /// Desaturates by an amount (0-1), fully for greyscale
+    Desaturate(f64),
+    /// Multiplies the opacity
+    Opacity(f64),
+    /// Greyscale multiplied by a colour
+    Tint(svgtypes::Color),
+    /// Row-major 4x5 matrix, as in `feColorMatrix`
+    Matrix([f64; 20]),
+}
+
+/// Parses an amount given as a fraction or a percentage.
+fn parse_amount(s: &str) -> Result<f64, String> {
+    let value = match s.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>().map(|v| v / 100.),
+        None => s.parse::<f64>(),
+    };
+    value.ok().filter(|v| (0. ..=1.).contains(v))
+        .ok_or_else(|| format!("Invalid amount {:?}, expected 0-1 or 0-100%", s))
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, argument) = match s.strip_suffix(')').and_then(|s| s.split_once('(')) {
+            Some((name, argument)) => (name, Some(argument.trim())),
+            None => (s, None),
+        };
+        Ok(match (name.to_ascii_lowercase().as_str(), argument) {
+            ("greyscale" | "grayscale", None) => Filter::Desaturate(1.),
+            ("greyscale" | "grayscale" | "desaturate", Some(amount)) => {
+                Filter::Desaturate(parse_amount(amount)?)
+            },
+            ("opacity", Some(amount)) => Filter::Opacity(parse_amount(amount)?),
+            ("tint", Some(color)) => Filter::Tint(color.parse()
+                .map_err(|e| format!("Invalid colour {:?}: {}", color, e))?),
+            ("matrix", Some(values)) => {
+                let values: Vec<f64> = values.split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|v| !v.is_empty())
+                    .map(|v| v.parse::<f64>())
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format!("Invalid colour matrix {:?}: {}", values, e))?;
+                let mut matrix = [0.; 20];
+                if values.len() != matrix.len() {
+                    return Err(format!("Invalid colour matrix {:?}, expected 20 values", s));
+                }
+                matrix.copy_from_slice(&values);
+                Filter::Matrix(matrix)
+            },
+            _ => return Err(format!(
+                "Unknown filter {:?}, expected greyscale, desaturate(AMOUNT), opacity(AMOUNT), \
+                 tint(COLOR) or matrix(20 VALUES)", s)),
+        })
+    }
+}
+
+impl Filter {
+    fn matrix(&self) -> [f64; 20] {
+        // Luminance coefficients of the feColorMatrix saturate type
+        let (lr, lg, lb) = (0.213, 0.715, 0.072);
+        match *self {
+            Filter::Desaturate(amount) => {
+                let s = 1. - amount;
+                [lr + (1. - lr) * s, lg - lg * s, lb - lb * s, 0., 0.,
+                 lr - lr * s, lg + (1. - lg) * s, lb - lb * s, 0., 0.,
+                 lr - lr * s, lg - lg * s, lb + (1. - lb) * s, 0., 0.,
+                 0., 0., 0., 1., 0.]
+            },
+            Filter::Opacity(amount) => {
+                [1., 0., 0., 0., 0.,
+                 0., 1., 0., 0., 0.,
+                 0., 0., 1., 0., 0.,
+                 0., 0., 0., amount, 0.]
+            },
+            Filter::Tint(color) => {
+                let [r, g, b, a] = [color.red, color.green, color.blue, color.alpha]
+                    .map(|c| c as f64 / 255.);
+                [r * lr, r * lg, r * lb, 0., 0.,
+                 g * lr, g * lg, g * lb, 0., 0.,
+                 b * lr, b * lg, b * lb, 0., 0.,
+                 0., 0., 0., a, 0.]
+            },
+            Filter::Matrix(matrix) => matrix,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant {
+    /// Directory of the icons having the variant, all icons if not given
+    dir: Option<PathBuf>,
+    pub suffix: String,
+    filters: Vec<Filter>,
+}
+
+/// Splits a filter list on the whitespace outside of the filter arguments,
+/// which may contain spaces themselves (like `tint(rgb(0, 102, 255))`).
+fn split_filters(s: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut depth = 0usize;
+    let mut start = None;
+    for (idx, c) in s.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            _ if c.is_whitespace() && depth == 0 => {
+                if let Some(start) = start.take() {
+                    result.push(&s[start..idx]);
+                }
+                continue;
+            },
+            _ => {},
+        }
+        start.get_or_insert(idx);
+    }
+    if let Some(start) = start {
+        result.push(&s[start..]);
+    }
+    result
+}
+
+impl FromStr for Variant {
+    type Err = String;
+
+    /// Parses `[DIR=]SUFFIX:FILTERS` with the filters separated by spaces.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid variant {:?}, expected [DIR=]SUFFIX:FILTERS", s);
+        let (head, filters) = s.split_once(':').ok_or_else(invalid)?;
+        let (dir, suffix) = match head.rsplit_once('=') {
+            Some((dir, suffix)) => (Some(PathBuf::from(dir)), suffix),
+            None => (None, head),
+        };
+        let filters: Vec<Filter> = split_filters(filters).into_iter()
+            .map(Filter::from_str)
+            .collect::<Result<_, _>>()?;
+        if suffix.is_empty() || filters.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self { dir, suffix: suffix.to_owned(), filters })
+    }
+}
+
+impl Variant {
+    /// Returns `true` if the icon of an input file has the variant.
+    pub fn applies_to(&self, path: &Path) -> bool {
+        self.dir.as_ref().map_or(true, |dir| path.starts_with(dir))
+    }
+
+    /// Applies the filters to an icon image.
+    pub fn apply(&self, pixmap: &mut Pixmap) {
+        let matrices: Vec<[f64; 20]> = self.filters.iter().map(Filter::matrix).collect();
+        for pixel in pixmap.pixels_mut() {
+            if pixel.alpha() == 0 && matrices.iter().all(|m| m[19] == 0.) {
+                continue;
+            }
+            let color = pixel.demultiply();
+            let mut c = [color.red(), color.green(), color.blue(), color.alpha()]
+                .map(|v| v as f64 / 255.);
+            for m in matrices.iter() {
+                let row = |i: usize| {
+                    let value = m[i * 5] * c[0] + m[i * 5 + 1] * c[1] + m[i * 5 + 2] * c[2]
+                        + m[i * 5 + 3] * c[3] + m[i * 5 + 4];
+                    value.clamp(0., 1.)
+                };
+                c = [row(0), row(1), row(2), row(3)];
+            }
+            let [r, g, b, a] = c.map(|v| (v * 255.).round() as u8);
+            *pixel = ColorU8::from_rgba(r, g, b, a).premultiply();
+        }
+    }
+}
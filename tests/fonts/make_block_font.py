@@ -0,0 +1,71 @@
+#!/usr/bin/env python3
+"""Writes Block.ttf, a minimal TrueType font for the text tests.
+
+The font family is "Block"; "A" is a filled square (100,0)-(900,800)
+in 1000 units per em with an advance of 1000, other characters are missing.
+"""
+
+import struct
+import sys
+
+
+def table_checksum(data):
+    data += b'\0' * (-len(data) % 4)
+    return sum(struct.unpack('>%dI' % (len(data) // 4), data)) & 0xFFFFFFFF
+
+
+def font():
+    square = struct.pack('>hhhhhH', 1, 100, 0, 900, 800, 3) + struct.pack('>H', 0)
+    square += bytes([0x01] * 4)
+    square += struct.pack('>4h', 100, 0, 800, 0) + struct.pack('>4h', 0, 800, 0, -800)
+    glyf = square + b'\0' * (-len(square) % 4)
+    # .notdef is empty
+    loca = struct.pack('>3H', 0, 0, len(glyf) // 2)
+
+    head = struct.pack('>IIIIHHQQhhhhHHhhh', 0x00010000, 0x00010000, 0, 0x5F0F3CF5, 0x000B,
+                       1000, 0, 0, 0, 0, 1000, 800, 0, 3, 2, 0, 0)
+    hhea = struct.pack('>Ihhh H hhh hhh 4h hH', 0x00010000, 800, -200, 0,
+                       1000, 0, 100, 900, 1, 0, 0, 0, 0, 0, 0, 0, 2)
+    maxp = struct.pack('>IH13H', 0x00010000, 2, 4, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0)
+    hmtx = struct.pack('>HhHh', 1000, 0, 1000, 100)
+    os2 = struct.pack('>HhHHH10hh10s4I4sHHH3h2H',
+                      0, 500, 400, 5, 0, 650, 600, 0, 75, 650, 600, 0, 350, 50, 250, 0,
+                      b'\0' * 10, 0, 0, 0, 0, b'NONE', 0x40, 0x41, 0x41, 800, -200, 0, 800, 200)
+    post = struct.pack('>IIhhIIIII', 0x00030000, 0, -100, 50, 0, 0, 0, 0, 0)
+
+    # format 4 subtable mapping "A" (0x41) to glyph 1
+    segments = [(0x41, 0x41, 1 - 0x41), (0xFFFF, 0xFFFF, 1)]
+    seg_x2 = len(segments) * 2
+    cmap4 = struct.pack('>7H', 4, 16 + seg_x2 * 4, 0, seg_x2, 4, 1, 0)
+    cmap4 += struct.pack('>%dH' % len(segments), *[end for _, end, _ in segments])
+    cmap4 += struct.pack('>H', 0)
+    cmap4 += struct.pack('>%dH' % len(segments), *[start for start, _, _ in segments])
+    cmap4 += struct.pack('>%dh' % len(segments), *[delta for _, _, delta in segments])
+    cmap4 += struct.pack('>%dH' % len(segments), *[0] * len(segments))
+    cmap = struct.pack('>HHHHI', 0, 1, 3, 1, 12) + cmap4
+
+    names = [(1, 'Block'), (2, 'Regular'), (4, 'Block'), (6, 'Block')]
+    strings = b''
+    records = b''
+    for name_id, value in names:
+        encoded = value.encode('utf-16-be')
+        records += struct.pack('>6H', 3, 1, 0x409, name_id, len(encoded), len(strings))
+        strings += encoded
+    name = struct.pack('>HHH', 0, len(names), 6 + len(records)) + records + strings
+
+    tables = {b'OS/2': os2, b'cmap': cmap, b'glyf': glyf, b'head': head, b'hhea': hhea,
+              b'hmtx': hmtx, b'loca': loca, b'maxp': maxp, b'name': name, b'post': post}
+    offset = 12 + 16 * len(tables)
+    directory = struct.pack('>IHHHH', 0x00010000, len(tables), 128, 3, len(tables) * 16 - 128)
+    data = b''
+    for tag in sorted(tables):
+        table = tables[tag]
+        directory += struct.pack('>4sIII', tag, table_checksum(table), offset + len(data),
+                                 len(table))
+        data += table + b'\0' * (-len(table) % 4)
+    return directory + data
+
+
+if __name__ == '__main__':
+    with open(sys.argv[1] if len(sys.argv) > 1 else 'Block.ttf', 'wb') as f:
+        f.write(font())